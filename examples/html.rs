@@ -1,27 +1,22 @@
-use std::{
-    collections::{BTreeMap, HashMap},
-    pin::Pin,
-};
-
-use moon_class::{
-    util::{executor::ClassExecutor, rs_2_str},
-    AsClassManager, ClassManager, Fu,
-};
-use view_manager::{
-    bean::{VNode, ViewProps},
-    def::{AsElementProvider, AsViewManager},
-};
+use moon_class::{util::executor::ClassExecutor, ClassManager};
+use view_manager::{bean::ViewProps, def::AsElementProvider, manager::StandardViewManager};
 
 mod inner {
     use view_manager::def::AsViewManager;
 
-    use crate::ViewManager;
-
-    pub fn ser_html(space: &str, id: u64, vm: &ViewManager) -> String {
+    pub fn ser_html(space: &str, id: u64, vm: &impl AsViewManager) -> String {
         let vnode = vm.get_vnode(&id).unwrap();
-        if vnode.inner_id != 0 {
+        if let Some(inner_id) = vnode.inner_id_op {
             // virtual container
-            ser_html(&format!("{space}{space}"), vnode.inner_id, vm)
+            ser_html(&format!("{space}{space}"), inner_id, vm)
+        } else if vnode.view_props.class == "$fragment" {
+            // fragment of a view
+            vnode
+                .embeded_child_v
+                .iter()
+                .map(|child_node| ser_html(space, *child_node, vm))
+                .collect::<Vec<String>>()
+                .join("\n")
         } else {
             // meta container
             let mut html = format!("{space}<{}>", vnode.view_props.class);
@@ -34,79 +29,9 @@ mod inner {
     }
 }
 
-struct InnerViewManager {
-    unique_id: u64,
-    vnode_mp: HashMap<u64, VNode>,
-}
-
-struct ViewManager {
-    inner: InnerViewManager,
-    cm: Box<dyn AsClassManager>,
-    dirty_vnode_v: BTreeMap<u64, Option<ViewProps>>,
-}
-
-impl ViewManager {
-    fn new(dm: impl AsClassManager + 'static) -> Self {
-        Self {
-            inner: InnerViewManager {
-                unique_id: 0,
-                vnode_mp: HashMap::new(),
-            },
-            cm: Box::new(dm),
-            dirty_vnode_v: BTreeMap::new(),
-        }
-    }
-
-    pub async fn init(&mut self, entry: ViewProps) {
-        let root_id = self.new_vnode(VNode::new(0, None));
-        self.apply_props(root_id, Some(entry)).await.unwrap();
-    }
-}
-
-impl AsClassManager for ViewManager {
-    fn remove<'a, 'a1, 'a2, 'f>(
-        &'a mut self,
-        class: &'a1 str,
-        source: &'a2 str,
-        target_v: Vec<String>,
-    ) -> Pin<Box<dyn moon_class::Fu<Output = moon_class::err::Result<()>> + 'f>>
-    where
-        'a: 'f,
-        'a1: 'f,
-        'a2: 'f,
-    {
-        self.cm.remove(class, source, target_v)
-    }
-
-    fn get<'a, 'a1, 'a2, 'f>(
-        &'a self,
-        class: &'a1 str,
-        source: &'a2 str,
-    ) -> Pin<Box<dyn moon_class::Fu<Output = moon_class::err::Result<Vec<String>>> + 'f>>
-    where
-        'a: 'f,
-        'a1: 'f,
-        'a2: 'f,
-    {
-        self.cm.get(class, source)
-    }
-
-    fn append<'a, 'a1, 'a2, 'f>(
-        &'a mut self,
-        class: &'a1 str,
-        pair: &'a2 str,
-        item_v: Vec<String>,
-    ) -> Pin<Box<dyn moon_class::Fu<Output = moon_class::err::Result<()>> + 'f>>
-    where
-        'a: 'f,
-        'a1: 'f,
-        'a2: 'f,
-    {
-        self.cm.append(class, pair, item_v)
-    }
-}
+struct HtmlProvider;
 
-impl AsElementProvider for ViewManager {
+impl AsElementProvider for HtmlProvider {
     type H = u64;
 
     fn reuse_element(&mut self, id: u64, _class: &str, _props: &json::JsonValue) -> bool {
@@ -124,48 +49,13 @@ impl AsElementProvider for ViewManager {
 
         vnode_id
     }
-}
-
-impl AsViewManager for ViewManager {
-    fn get_class_view<'a, 'a1, 'f>(
-        &'a self,
-        class: &'a1 str,
-    ) -> Pin<Box<dyn Fu<Output = Option<String>> + 'f>>
-    where
-        'a: 'f,
-        'a1: 'f,
-    {
-        Box::pin(async move {
-            let rs = self.get("view", class).await.unwrap();
-            if rs.is_empty() {
-                None
-            } else {
-                Some(rs_2_str(&rs))
-            }
-        })
-    }
 
-    fn get_vnode(&self, id: &u64) -> Option<&VNode> {
-        self.inner.vnode_mp.get(id)
+    fn move_element(&mut self, id: u64, parent: u64, index: usize) {
+        log::debug!("move_element: id = {id}, parent = {parent}, index = {index}");
     }
 
-    fn get_vnode_mut(&mut self, id: &u64) -> Option<&mut VNode> {
-        self.inner.vnode_mp.get_mut(id)
-    }
-
-    fn new_vnode(&mut self, vnode: VNode) -> u64 {
-        let new_id = self.inner.unique_id;
-        self.inner.unique_id += 1;
-        self.inner.vnode_mp.insert(new_id, vnode);
-        new_id
-    }
-
-    fn rm_vnode(&mut self, id: u64) -> Option<VNode> {
-        self.inner.vnode_mp.remove(&id)
-    }
-
-    fn dirty_vnode_v_mut(&mut self) -> &mut BTreeMap<u64, Option<ViewProps>> {
-        &mut self.dirty_vnode_v
+    fn attach_portal(&mut self, id: u64, target: &str) {
+        log::debug!("attach_portal: id = {id}, target = {target}");
     }
 }
 
@@ -186,7 +76,7 @@ fn main() {
             props: json::Null,
         };
 
-        let mut vm = ViewManager::new(ClassManager::new());
+        let mut vm = StandardViewManager::new(ClassManager::new(), HtmlProvider);
 
         let mut ce = ClassExecutor::new(&mut vm);
 
@@ -223,7 +113,7 @@ fn main() {
             }
         ],
         $props: {
-            $type: set
+            $type: ordered-set
         }
     } = $result();
 > = view(Box);
@@ -232,20 +122,8 @@ fn main() {
         .await
         .unwrap();
 
-        vm.init(entry).await;
-
-        loop {
-            let dirty_vnode_v = std::mem::take(vm.dirty_vnode_v_mut());
-
-            if dirty_vnode_v.is_empty() {
-                break;
-            }
-
-            for (vnode_id, view_props_op) in dirty_vnode_v {
-                vm.apply_props(vnode_id, view_props_op).await.unwrap();
-            }
-        }
+        let root_id = vm.init(entry).await.unwrap();
 
-        println!("{}", inner::ser_html("  ", 0, &vm));
+        println!("{}", inner::ser_html("  ", root_id, &vm));
     })
 }
@@ -1,9 +1,49 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Instant,
+};
+
+use error_stack::ResultExt;
+
+use crate::err;
+
 #[derive(PartialEq, Clone, Debug, Eq)]
 pub struct ViewProps {
     pub class: String,
     pub props: json::JsonValue,
 }
 
+impl ViewProps {
+    /// The value of the prop `name`, unwrapped from the one-item array scripts produce.
+    pub fn prop(&self, name: &str) -> &json::JsonValue {
+        let value = &self.props[name];
+
+        if value.is_array() {
+            &value[0]
+        } else {
+            value
+        }
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            class: self.class.clone(),
+            props: self.props.clone(),
+        }
+    }
+
+    pub fn from_json(value: &json::JsonValue) -> err::Result<Self> {
+        Ok(Self {
+            class: value["class"]
+                .as_str()
+                .ok_or(err::Error::RuntimeError)
+                .attach_printable_lazy(|| format!("view props without class: {value}"))?
+                .to_string(),
+            props: value["props"].clone(),
+        })
+    }
+}
+
 impl Ord for ViewProps {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.partial_cmp(other).unwrap()
@@ -20,15 +60,28 @@ impl PartialOrd for ViewProps {
     }
 }
 
+/// What a layout was computed from.
+#[derive(PartialEq, Clone, Debug)]
+pub struct LayoutInput {
+    pub view_props: ViewProps,
+    pub state: json::JsonValue,
+    pub embeded_child_v: Vec<u64>,
+}
+
 #[derive(Clone)]
 pub struct VNode {
     pub view_props: ViewProps,
     pub state: json::JsonValue,
-    pub inner_id: u64,
+    /// The root of its layout when its class has a view.
+    pub inner_id_op: Option<u64>,
     pub embeded_child_v: Vec<u64>,
     pub context: u64,
     pub is_dirty: bool,
     pub parent_op: Option<u64>,
+    /// Input of the last layout when its view is `$pure`.
+    pub last_input_op: Option<LayoutInput>,
+    /// (class, source) pairs its last layout read.
+    pub read_set: BTreeSet<(String, String)>,
 }
 
 impl VNode {
@@ -39,11 +92,577 @@ impl VNode {
                 props: json::Null,
             },
             state: json::object! {},
-            inner_id: 0,
+            inner_id_op: None,
             embeded_child_v: vec![],
             context,
             is_dirty: true,
             parent_op,
+            last_input_op: None,
+            read_set: BTreeSet::new(),
+        }
+    }
+
+    /// Everything but `last_input_op`, which only saves work.
+    pub fn to_json(&self) -> json::JsonValue {
+        json::object! {
+            view_props: self.view_props.to_json(),
+            state: self.state.clone(),
+            inner_id: self.inner_id_op,
+            embeded_child_v: self.embeded_child_v.clone(),
+            context: self.context,
+            is_dirty: self.is_dirty,
+            parent: self.parent_op,
+            read_set: self
+                .read_set
+                .iter()
+                .map(|(class, source)| json::array![class.as_str(), source.as_str()])
+                .collect::<Vec<json::JsonValue>>(),
+        }
+    }
+
+    pub fn from_json(value: &json::JsonValue) -> err::Result<Self> {
+        let embeded_child_v = value["embeded_child_v"]
+            .members()
+            .map(|id| id.as_u64())
+            .collect::<Option<Vec<u64>>>()
+            .ok_or(err::Error::RuntimeError)
+            .attach_printable_lazy(|| format!("invalid embeded_child_v in vnode {value}"))?;
+        let read_set = value["read_set"]
+            .members()
+            .map(|pair| Some((pair[0].as_str()?.to_string(), pair[1].as_str()?.to_string())))
+            .collect::<Option<BTreeSet<(String, String)>>>()
+            .ok_or(err::Error::RuntimeError)
+            .attach_printable_lazy(|| format!("invalid read_set in vnode {value}"))?;
+
+        Ok(Self {
+            view_props: ViewProps::from_json(&value["view_props"])?,
+            state: value["state"].clone(),
+            inner_id_op: value["inner_id"].as_u64(),
+            embeded_child_v,
+            context: value["context"]
+                .as_u64()
+                .ok_or(err::Error::RuntimeError)
+                .attach_printable_lazy(|| format!("vnode without context: {value}"))?,
+            is_dirty: value["is_dirty"].as_bool().unwrap_or(false),
+            parent_op: value["parent"].as_u64(),
+            last_input_op: None,
+            read_set,
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Slot {
+    generation: u32,
+    vnode_op: Option<VNode>,
+    /// Kept out of the free list for `insert_reserved`.
+    is_reserved: bool,
+}
+
+/// Vnodes by id, the store behind `StandardViewManager`.
+///
+/// An id holds the index of a slot in its low 32 bits and the generation of the slot in its
+/// high ones. A slot is reused after its vnode is removed, with the next generation, so the ids
+/// of removed vnodes never find the new ones.
+#[derive(Default, Clone)]
+pub struct VNodeArena {
+    slot_v: Vec<Slot>,
+    free_v: Vec<u32>,
+}
+
+impl VNodeArena {
+    fn split_id(id: u64) -> (usize, u32) {
+        ((id & u32::MAX as u64) as usize, (id >> 32) as u32)
+    }
+
+    fn join_id(index: usize, generation: u32) -> u64 {
+        ((generation as u64) << 32) | index as u64
+    }
+
+    pub fn get(&self, id: &u64) -> Option<&VNode> {
+        let (index, generation) = Self::split_id(*id);
+
+        self.slot_v
+            .get(index)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.vnode_op.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: &u64) -> Option<&mut VNode> {
+        let (index, generation) = Self::split_id(*id);
+
+        self.slot_v
+            .get_mut(index)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.vnode_op.as_mut())
+    }
+
+    /// Stores `vnode` under a new id.
+    pub fn insert(&mut self, vnode: VNode) -> u64 {
+        match self.free_v.pop() {
+            Some(index) => {
+                let slot = &mut self.slot_v[index as usize];
+
+                slot.vnode_op = Some(vnode);
+
+                Self::join_id(index as usize, slot.generation)
+            }
+            None => {
+                self.slot_v.push(Slot {
+                    generation: 0,
+                    vnode_op: Some(vnode),
+                    is_reserved: false,
+                });
+
+                Self::join_id(self.slot_v.len() - 1, 0)
+            }
+        }
+    }
+
+    /// Adds free slots up to `index`.
+    fn grow(&mut self, index: usize) {
+        while self.slot_v.len() <= index {
+            self.free_v.push(self.slot_v.len() as u32);
+            self.slot_v.push(Slot {
+                generation: 0,
+                vnode_op: None,
+                is_reserved: false,
+            });
+        }
+    }
+
+    /// Stores `vnode` under `id` again, to undo its removal; fails when the slot holds a vnode.
+    pub fn insert_at(&mut self, id: u64, vnode: VNode) -> err::Result<()> {
+        let (index, generation) = Self::split_id(id);
+
+        self.grow(index);
+
+        if self.slot_v[index].vnode_op.is_some() {
+            return Err(err::Error::RuntimeError)
+                .attach_printable_lazy(|| format!("slot of {id} holds a vnode"));
         }
+
+        self.free_v.retain(|free| *free as usize != index);
+        self.slot_v[index] = Slot {
+            generation,
+            vnode_op: Some(vnode),
+            is_reserved: false,
+        };
+
+        Ok(())
+    }
+
+    /// Keeps `insert` from handing out the ids in `id_v`, for `insert_reserved`.
+    ///
+    /// Ids whose slot holds a vnode, or handed out already, are skipped: a slot only moves on
+    /// to later generations.
+    pub fn reserve(&mut self, id_v: &[u64]) {
+        let mut index_set = BTreeSet::new();
+
+        for id in id_v {
+            let (index, generation) = Self::split_id(*id);
+
+            self.grow(index);
+
+            let slot = &mut self.slot_v[index];
+
+            if slot.vnode_op.is_some() || slot.is_reserved || slot.generation > generation {
+                continue;
+            }
+
+            slot.generation = generation;
+            slot.is_reserved = true;
+            index_set.insert(index);
+        }
+
+        self.free_v
+            .retain(|free| !index_set.contains(&(*free as usize)));
+    }
+
+    /// Frees the ids of `id_v` that are still reserved.
+    pub fn release(&mut self, id_v: &[u64]) {
+        for id in id_v {
+            let (index, generation) = Self::split_id(*id);
+
+            if let Some(slot) = self
+                .slot_v
+                .get_mut(index)
+                .filter(|slot| slot.is_reserved && slot.generation == generation)
+            {
+                slot.is_reserved = false;
+                self.free_v.push(index as u32);
+            }
+        }
+    }
+
+    /// Stores `vnode` under `id`, which `reserve` kept.
+    pub fn insert_reserved(&mut self, id: u64, vnode: VNode) -> err::Result<()> {
+        let (index, generation) = Self::split_id(id);
+
+        let slot = self
+            .slot_v
+            .get_mut(index)
+            .filter(|slot| slot.is_reserved && slot.generation == generation)
+            .ok_or(err::Error::NotFound)
+            .attach_printable_lazy(|| format!("{id} is not reserved"))?;
+
+        slot.is_reserved = false;
+        slot.vnode_op = Some(vnode);
+
+        Ok(())
+    }
+
+    /// Every slot with its generation, and the free list, so that ids survive a `restore`.
+    pub fn snapshot(&self) -> json::JsonValue {
+        json::object! {
+            slot_v: self
+                .slot_v
+                .iter()
+                .map(|slot| {
+                    json::object! {
+                        generation: slot.generation,
+                        vnode: slot.vnode_op.as_ref().map(VNode::to_json),
+                    }
+                })
+                .collect::<Vec<json::JsonValue>>(),
+            free_v: self.free_v.clone(),
+        }
+    }
+
+    pub fn restore(snapshot: &json::JsonValue) -> err::Result<Self> {
+        let mut slot_v = vec![];
+
+        for slot in snapshot["slot_v"].members() {
+            let generation = slot["generation"]
+                .as_u32()
+                .ok_or(err::Error::RuntimeError)
+                .attach_printable_lazy(|| format!("slot without generation: {slot}"))?;
+            let vnode_op = if slot["vnode"].is_null() {
+                None
+            } else {
+                Some(VNode::from_json(&slot["vnode"])?)
+            };
+
+            slot_v.push(Slot {
+                generation,
+                vnode_op,
+                is_reserved: false,
+            });
+        }
+
+        let mut free_set = BTreeSet::new();
+
+        // Each free slot once, and empty, or `insert` would overwrite a vnode.
+        let free_v = snapshot["free_v"]
+            .members()
+            .map(|index| {
+                index.as_u32().filter(|index| {
+                    slot_v
+                        .get(*index as usize)
+                        .is_some_and(|slot| slot.vnode_op.is_none())
+                        && free_set.insert(*index)
+                })
+            })
+            .collect::<Option<Vec<u32>>>()
+            .ok_or(err::Error::RuntimeError)
+            .attach_printable_lazy(|| format!("invalid free_v: {}", snapshot["free_v"]))?;
+
+        let arena = Self { slot_v, free_v };
+
+        arena.check_link()?;
+
+        Ok(arena)
+    }
+
+    /// Fails when a vnode refers to one that is not stored.
+    fn check_link(&self) -> err::Result<()> {
+        for (index, slot) in self.slot_v.iter().enumerate() {
+            let vnode = match &slot.vnode_op {
+                Some(r) => r,
+                None => {
+                    continue;
+                }
+            };
+
+            let id = Self::join_id(index, slot.generation);
+            let link_v = vnode
+                .embeded_child_v
+                .iter()
+                .chain(&vnode.inner_id_op)
+                .chain(&vnode.parent_op)
+                .chain([&vnode.context]);
+
+            for link in link_v {
+                if self.get(link).is_none() {
+                    return Err(err::Error::NotFound)
+                        .attach_printable_lazy(|| format!("vnode {id} refers to missing {link}"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<VNode> {
+        let (index, generation) = Self::split_id(id);
+
+        let slot = self
+            .slot_v
+            .get_mut(index)
+            .filter(|slot| slot.generation == generation)?;
+        let vnode = slot.vnode_op.take()?;
+
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free_v.push(index as u32);
+
+        Some(vnode)
+    }
+}
+
+/// An element operation decided while rendering, run against the provider at commit.
+#[derive(Clone, Debug)]
+pub enum ElementOp {
+    /// `id` changes from `old_class` to `view_props`, see `on_update_vnode_props`.
+    Update {
+        id: u64,
+        old_class: String,
+        view_props: ViewProps,
+    },
+    /// `id` goes to `index` among the children of `parent`, see `move_element`.
+    Move {
+        id: u64,
+        parent: u64,
+        index: usize,
+    },
+    Delete {
+        id: u64,
+    },
+    /// `id` takes over the element the provider already has, see `AsViewManager::hydrate_from`.
+    Adopt {
+        id: u64,
+        view_props: ViewProps,
+    },
+}
+
+/// An element the provider has before the first commit, see `AsElementProvider::host_tree`.
+#[derive(Clone, Debug)]
+pub struct HostNode {
+    /// The vnode it was created for.
+    pub id: u64,
+    pub class: String,
+    pub child_v: Vec<HostNode>,
+}
+
+/// A difference between the vnodes and the host tree found while hydrating.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The vnode `id` of `class` meets the element `host_id` of `host_class`; it is replaced.
+    Differ {
+        id: u64,
+        class: String,
+        host_id: u64,
+        host_class: String,
+    },
+    /// The vnode `id` has no element yet; it is created.
+    Missing { id: u64, class: String },
+    /// The element `host_id` has no vnode; it is deleted with its children.
+    Extra { host_id: u64, host_class: String },
+}
+
+/// Bookkeeping of the flush in progress: vnodes as they were before it, to undo it when it
+/// fails, and layouts run ahead.
+#[derive(Default, Clone)]
+pub struct Journal {
+    /// Vnodes are only recorded while a flush is in progress.
+    pub is_open: bool,
+    /// The first version of every vnode the flush touched, `None` for the ones it created.
+    pub origin_mp: BTreeMap<u64, Option<VNode>>,
+    /// Results of layouts run together with their siblings, with the script each ran; dropped
+    /// when a pair they read is written, see `AsViewManager::notify_write`.
+    pub layout_mp: BTreeMap<u64, (String, ScriptOutput)>,
+    /// The element children of every vnode whose children the flush changed, as they were
+    /// before it, to place them at its end.
+    pub element_child_mp: BTreeMap<u64, Vec<u64>>,
+    /// While hydrating, the host node of every vnode that took the id of one, to pair the
+    /// vnodes created under it with its children; see `AsViewManager::hydrate_from`.
+    pub host_mp: BTreeMap<u64, HostNode>,
+    /// The pairs written during the flush, to dirty their readers again if it fails.
+    pub write_set: BTreeSet<(String, String)>,
+    /// How many times the flush applied every vnode, to tell a cycle.
+    pub apply_count_mp: BTreeMap<u64, usize>,
+}
+
+/// What a layout script returned, dumped, and the (class, source) pairs it read and wrote.
+#[derive(Clone, Debug)]
+pub struct ScriptOutput {
+    pub root: json::JsonValue,
+    pub read_set: BTreeSet<(String, String)>,
+    pub write_set: BTreeSet<(String, String)>,
+}
+
+/// What a flush did.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RenderStat {
+    /// Dirty vnodes handed to `apply_props`.
+    pub apply_count: usize,
+    /// Layouts saved because an ancestor dirtied a vnode again, or removed it, before its turn.
+    pub saved_count: usize,
+    /// Dirty vnodes left for a later slice.
+    pub pending_count: usize,
+}
+
+/// How much a slice of rendering may do before it yields, unlimited by default.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Budget {
+    /// Most vnodes to apply.
+    pub max_apply_op: Option<usize>,
+    /// No vnode is started after it.
+    pub deadline_op: Option<Instant>,
+}
+
+impl Budget {
+    pub fn is_spent(&self, stat: &RenderStat) -> bool {
+        self.max_apply_op.is_some_and(|max| stat.apply_count >= max)
+            || self
+                .deadline_op
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vnode() -> VNode {
+        VNode::new(0, None)
+    }
+
+    #[test]
+    fn test_remove_and_reuse() {
+        let mut arena = VNodeArena::default();
+
+        let a = arena.insert(vnode());
+
+        assert!(arena.remove(a).is_some());
+        assert!(arena.get(&a).is_none());
+        assert!(arena.remove(a).is_none());
+
+        let b = arena.insert(vnode());
+
+        // Same slot, next generation.
+        assert_eq!(VNodeArena::split_id(b), (VNodeArena::split_id(a).0, 1));
+        assert!(arena.get(&b).is_some());
+    }
+
+    #[test]
+    fn test_stale_id() {
+        let mut arena = VNodeArena::default();
+
+        let a = arena.insert(vnode());
+
+        arena.remove(a);
+
+        let b = arena.insert(vnode());
+
+        assert!(arena.get(&a).is_none());
+        assert!(arena.get_mut(&a).is_none());
+        assert!(arena.remove(a).is_none());
+        assert!(arena.get(&b).is_some());
+    }
+
+    #[test]
+    fn test_insert_at() {
+        let mut arena = VNodeArena::default();
+
+        let a = arena.insert(vnode());
+
+        arena.remove(a);
+        arena.insert_at(a, vnode()).unwrap();
+
+        assert!(arena.get(&a).is_some());
+
+        // Whatever the generation, a slot holds one vnode.
+        assert!(arena.insert_at(a + (1 << 32), vnode()).is_err());
+
+        // The slot is taken again, a new vnode goes elsewhere.
+        let b = arena.insert(vnode());
+
+        assert_ne!(VNodeArena::split_id(b).0, VNodeArena::split_id(a).0);
+
+        // Slots skipped on the way are free.
+        let far = VNodeArena::join_id(4, 2);
+
+        arena.insert_at(far, vnode()).unwrap();
+
+        assert!(arena.get(&far).is_some());
+        assert_eq!(arena.free_v.len(), 2);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut arena = VNodeArena::default();
+
+        let a = arena.insert(vnode());
+
+        arena.remove(a);
+
+        let old = VNodeArena::join_id(0, 0);
+        let far = VNodeArena::join_id(3, 1);
+
+        arena.reserve(&[old, far]);
+
+        // `old` was handed out already.
+        assert!(arena.insert_reserved(old, vnode()).is_err());
+
+        // New vnodes go around `far`.
+        let id_v = (0..3).map(|_| arena.insert(vnode())).collect::<Vec<u64>>();
+
+        assert!(id_v.iter().all(|id| VNodeArena::split_id(*id).0 != 3));
+
+        arena.insert_reserved(far, vnode()).unwrap();
+
+        assert!(arena.get(&far).is_some());
+        assert!(arena.insert_reserved(far, vnode()).is_err());
+
+        let next = VNodeArena::join_id(4, 0);
+
+        arena.reserve(&[next]);
+        arena.release(&[next]);
+
+        assert_eq!(arena.insert(vnode()), next);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut arena = VNodeArena::default();
+
+        let a = arena.insert(vnode());
+        let b = arena.insert(vnode());
+
+        arena.remove(a);
+        arena.get_mut(&b).unwrap().context = b;
+
+        let snapshot = arena.snapshot();
+        let arena = VNodeArena::restore(&snapshot).unwrap();
+
+        assert!(arena.get(&a).is_none());
+        assert!(arena.get(&b).is_some());
+        assert_eq!(arena.free_v, vec![0]);
+
+        let mut invalid = snapshot.clone();
+
+        invalid["free_v"] = json::array![0, 0];
+
+        assert!(VNodeArena::restore(&invalid).is_err());
+
+        invalid["free_v"] = json::array![1];
+
+        assert!(VNodeArena::restore(&invalid).is_err());
+
+        let mut invalid = snapshot;
+
+        invalid["slot_v"][1]["vnode"]["embeded_child_v"] = json::array![a];
+
+        assert!(VNodeArena::restore(&invalid).is_err());
     }
 }
@@ -1,15 +1,33 @@
-use std::{collections::BTreeMap, pin::Pin};
+use std::{collections::{BTreeMap, BTreeSet}, pin::Pin};
 
+use error_stack::ResultExt;
 use moon_class::{util::rs_2_str, def::{AsClassManager, Fu}};
 
-use crate::{bean::{VNode, ViewProps}, err};
+use crate::{bean::{Budget, ElementOp, HostNode, Journal, LayoutInput, Mismatch, RenderStat, ScriptOutput, VNode, ViewProps}, err};
 
-mod inner;
+pub(crate) mod inner;
+
+/// Times `flush` applies one vnode before it reports a cycle.
+pub const DEFAULT_MAX_ITERATION: usize = 64;
 
 pub trait AsViewManager: AsClassManager + AsElementProvider<H = u64> {
-    fn on_update_vnode_props(&mut self, id: u64, props: &ViewProps) {
+    /// Updates the element of `id`, whose class was `old_class`, to `props`; called at commit.
+    fn on_update_vnode_props(&mut self, id: u64, old_class: &str, props: &ViewProps) {
+        // A fragment only groups its children, it never has an element.
+        if old_class == "$fragment" {
+            if props.class != "$fragment" {
+                self.create_element(id, &props.class, &props.props);
+            }
+
+            return;
+        } else if props.class == "$fragment" {
+            self.delete_element(id);
+
+            return;
+        }
+
         // Let the element be usable.
-        if self.get_vnode(&id).unwrap().view_props.class != props.class {
+        if old_class != props.class {
             self.delete_element(id);
 
             self.create_element(id, &props.class, &props.props);
@@ -17,6 +35,17 @@ pub trait AsViewManager: AsClassManager + AsElementProvider<H = u64> {
             self.delete_element(id);
 
             self.create_element(id, &props.class, &props.props);
+
+            // Moves only place created elements, this one takes the place of the old one.
+            inner::place(self, id);
+        }
+
+        // A portal keeps its children here but shows them under another container.
+        if props.class == "$portal" {
+            match props.prop("$target").as_str() {
+                Some(target) => self.attach_portal(id, target),
+                None => log::warn!("portal {id} has no $target"),
+            }
         }
     }
 
@@ -35,41 +64,268 @@ pub trait AsViewManager: AsClassManager + AsElementProvider<H = u64> {
     {
         Box::pin(async move {
             log::debug!("event_entry: {entry_name}");
-            if let Some(vnode) = self.get_vnode(&vnode_id) {
-                let script = &vnode.view_props.props[entry_name];
+            let vnode = self
+                .get_vnode(&vnode_id)
+                .ok_or(err::Error::NotFound)
+                .attach_printable_lazy(|| format!("vnode with id {vnode_id} not found!"))?;
 
-                if script.is_empty() {
-                    return Ok(());
-                }
-
-                let script = if script.is_array() {
-                    rs_2_str(
-                        &script
-                            .members()
-                            .map(|jv| jv.as_str().unwrap().to_string())
-                            .collect::<Vec<String>>(),
-                    )
-                } else {
-                    script.as_str().unwrap().to_string()
-                };
+            let script = &vnode.view_props.props[entry_name];
 
-                inner::event_handler(self, data, vnode_id, script).await?;
+            if script.is_empty() {
+                return Ok(());
             }
+
+            let script = if script.is_array() {
+                rs_2_str(
+                    &script
+                        .members()
+                        .map(|jv| jv.as_str().unwrap().to_string())
+                        .collect::<Vec<String>>(),
+                )
+            } else {
+                script.as_str().unwrap().to_string()
+            };
+
+            inner::event_handler(self, data, vnode_id, script).await?;
+
             Ok(())
         })
     }
 
-    fn update_state(&mut self, vnode_id: u64, n_state: json::JsonValue) {
+    fn update_state(&mut self, vnode_id: u64, n_state: json::JsonValue) -> err::Result<()> {
         log::debug!("new state: {n_state} in {vnode_id}");
-        let vnode = self.get_vnode_mut(&vnode_id).unwrap();
+        let vnode = inner::vnode_mut(self, vnode_id)
+            .ok_or(err::Error::NotFound)
+            .attach_printable_lazy(|| format!("vnode with id {vnode_id} not found!"))?;
 
         vnode.state = n_state;
-        vnode.is_dirty = true;
-        self.dirty_vnode_v_mut().insert(vnode_id, None);
+        self.mark_dirty(vnode_id, None);
+
+        Ok(())
+    }
+
+    /// Merges `patch` into the state of `vnode_id` as a JSON merge patch (RFC 7386).
+    ///
+    /// The vnode is marked dirty only when its state changes.
+    fn patch_state(&mut self, vnode_id: u64, patch: &json::JsonValue) -> err::Result<()> {
+        let mut n_state = self
+            .get_vnode(&vnode_id)
+            .ok_or(err::Error::NotFound)
+            .attach_printable_lazy(|| format!("vnode with id {vnode_id} not found!"))?
+            .state
+            .clone();
+
+        inner::value::merge_patch(&mut n_state, patch);
+
+        if n_state != self.get_vnode(&vnode_id).unwrap().state {
+            self.update_state(vnode_id, n_state)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets `value` at `path`, like `a.b[2]`, in the state of `vnode_id`.
+    ///
+    /// Missing objects on the way are created. The vnode is marked dirty only when its state
+    /// changes.
+    fn set_state_path(
+        &mut self,
+        vnode_id: u64,
+        path: &str,
+        value: json::JsonValue,
+    ) -> err::Result<()> {
+        let mut n_state = self
+            .get_vnode(&vnode_id)
+            .ok_or(err::Error::NotFound)
+            .attach_printable_lazy(|| format!("vnode with id {vnode_id} not found!"))?
+            .state
+            .clone();
+
+        inner::value::set(&mut n_state, path, value)?;
+
+        if n_state != self.get_vnode(&vnode_id).unwrap().state {
+            self.update_state(vnode_id, n_state)?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs `f`, typically several `update_state`, then lays out everything it dirtied in one
+    /// flush; marks of the same vnode are merged, so each is laid out once.
+    fn batch<'a, 'f, F>(
+        &'a mut self,
+        f: F,
+    ) -> Pin<Box<dyn Fu<Output = err::Result<RenderStat>> + 'f>>
+    where
+        'a: 'f,
+        F: FnOnce(&mut Self),
+        Self: Sized,
+    {
+        f(self);
+
+        self.flush()
+    }
+
+    /// Schedules `vnode_id` for layout, with new props if `view_props_op` has some.
+    ///
+    /// Marks of a vnode that is already scheduled are merged, the newest props win.
+    fn mark_dirty(&mut self, vnode_id: u64, view_props_op: Option<ViewProps>) {
+        match inner::vnode_mut(self, vnode_id) {
+            Some(vnode) => vnode.is_dirty = true,
+            None => {
+                log::warn!("mark_dirty: vnode with id {vnode_id} not found!");
+
+                return;
+            }
+        }
+
+        let entry = self.dirty_vnode_v_mut().entry(vnode_id).or_insert(None);
+
+        if view_props_op.is_some() {
+            *entry = view_props_op;
+        }
     }
 
     fn dirty_vnode_v_mut(&mut self) -> &mut BTreeMap<u64, Option<ViewProps>>;
 
+    /// Vnodes whose last layout read each (class, source) pair.
+    ///
+    /// A vnode is taken out when it is removed or its layout stops reading a pair.
+    fn dep_mp_mut(&mut self) -> &mut BTreeMap<(String, String), BTreeSet<u64>>;
+
+    /// Marks dirty the vnodes whose last layout read `class` of `source`, even `$pure` ones.
+    ///
+    /// A manager reports every write made through it, by event and layout scripts alike, when
+    /// it is made, as `StandardViewManager` does in `append` and `remove`; `execute_layout_v`
+    /// reports the writes made to forks.
+    fn notify_write(&mut self, class: &str, source: &str) {
+        let pair = (class.to_string(), source.to_string());
+
+        inner::drop_ahead(self, &pair, None);
+
+        if self.journal_mut().is_open {
+            self.journal_mut().write_set.insert(pair.clone());
+        }
+
+        let id_set = match self.dep_mp_mut().remove(&pair) {
+            Some(r) => r,
+            None => {
+                return;
+            }
+        };
+
+        let mut n_id_set = BTreeSet::new();
+
+        for id in id_set {
+            if !self
+                .get_vnode(&id)
+                .is_some_and(|vnode| vnode.read_set.contains(&pair))
+            {
+                continue;
+            }
+
+            log::debug!("{class} of {source} changed, dirty {id}");
+
+            inner::vnode_mut(self, id).unwrap().last_input_op = None;
+            self.mark_dirty(id, None);
+
+            n_id_set.insert(id);
+        }
+
+        if !n_id_set.is_empty() {
+            self.dep_mp_mut().insert(pair, n_id_set);
+        }
+    }
+
+    /// Element operations rendered but not committed yet.
+    fn element_op_v_mut(&mut self) -> &mut Vec<ElementOp>;
+
+    /// Runs the element operations queued while rendering against the provider, in order.
+    ///
+    /// Rendering itself never calls the provider, so it only sees the element tree as of a
+    /// whole flush. Updates that keep the class run last, after the moves, unless the element
+    /// is deleted or changes class later on: the provider may create such an element again
+    /// instead of reusing it, and it is then put back in place, see `on_update_vnode_props`.
+    fn commit(&mut self) {
+        let op_v = std::mem::take(self.element_op_v_mut());
+        let mut gone_set = BTreeSet::new();
+        let mut is_last_v = vec![false; op_v.len()];
+
+        for (i, op) in op_v.iter().enumerate().rev() {
+            match op {
+                ElementOp::Update {
+                    id,
+                    old_class,
+                    view_props,
+                } if *old_class == view_props.class => is_last_v[i] = !gone_set.contains(id),
+                ElementOp::Update { id, .. } | ElementOp::Delete { id } => {
+                    gone_set.insert(*id);
+                }
+                _ => {}
+            }
+        }
+
+        let mut last_v = vec![];
+
+        for (op, is_last) in op_v.into_iter().zip(is_last_v) {
+            if is_last {
+                last_v.push(op);
+
+                continue;
+            }
+
+            match op {
+                ElementOp::Update {
+                    id,
+                    old_class,
+                    view_props,
+                } => self.on_update_vnode_props(id, &old_class, &view_props),
+                ElementOp::Move { id, parent, index } => self.move_element(id, parent, index),
+                ElementOp::Delete { id } => self.delete_element(id),
+                ElementOp::Adopt { id, view_props } => {
+                    self.adopt_element(id, &view_props.class, &view_props.props);
+
+                    if view_props.class == "$portal" {
+                        if let Some(target) = view_props.prop("$target").as_str() {
+                            self.attach_portal(id, target);
+                        }
+                    }
+                }
+            }
+        }
+
+        for op in last_v {
+            if let ElementOp::Update {
+                id,
+                old_class,
+                view_props,
+            } = op
+            {
+                self.on_update_vnode_props(id, &old_class, &view_props);
+            }
+        }
+    }
+
+    /// Reconcilers registered by the application, looked up by `$props.$type`.
+    fn reconciler_mp_mut(&mut self) -> &mut BTreeMap<String, Box<dyn AsReconciler>>;
+
+    /// Registers `reconciler` for children of nodes with `$props.$type` equal to `name`.
+    ///
+    /// The built-in "list", "set" and "ordered-set" are used when nothing is registered under
+    /// their names.
+    fn register_reconciler(&mut self, name: &str, reconciler: impl AsReconciler + 'static)
+    where
+        Self: Sized,
+    {
+        self.reconciler_mp_mut()
+            .insert(name.to_string(), Box::new(reconciler));
+    }
+
+    /// Lays out the dirty `vnode_id` with `view_props_op`, one step of a flush.
+    ///
+    /// Element operations are only queued, a flush commits them. Called on its own, outside a
+    /// flush, it is a flush of its own: it commits what it queued, or undoes it all when it
+    /// fails. The vnodes it dirties are left for the next flush.
     fn apply_props<'a, 'f>(
         &'a mut self,
         vnode_id: u64,
@@ -80,12 +336,27 @@ pub trait AsViewManager: AsClassManager + AsElementProvider<H = u64> {
         Self: Sized,
     {
         Box::pin(async move {
-            let vnode = match self.get_vnode_mut(&vnode_id) {
-                Some(r) => r,
-                None => {
-                    return Ok(());
-                }
-            };
+            if !self.journal_mut().is_open {
+                let backup = inner::begin(self);
+
+                return match self.apply_props(vnode_id, view_props_op).await {
+                    Ok(()) => {
+                        inner::end(self);
+
+                        Ok(())
+                    }
+                    Err(e) => {
+                        inner::rollback(self, backup);
+
+                        Err(e)
+                    }
+                };
+            }
+
+            let vnode_id = inner::settle(self, vnode_id, view_props_op.as_ref()).await;
+            let vnode = inner::vnode_mut(self, vnode_id)
+                .ok_or(err::Error::NotFound)
+                .attach_printable_lazy(|| format!("vnode with id {vnode_id} not found!"))?;
 
             if !vnode.is_dirty {
                 return Ok(());
@@ -95,53 +366,325 @@ pub trait AsViewManager: AsClassManager + AsElementProvider<H = u64> {
 
             let parent_op = vnode.parent_op;
 
-            let view_props = if let Some(view_props) = view_props_op {
-                let is_same_props = vnode.view_props == view_props;
-
-                if is_same_props {
-                    return Ok(());
+            if view_props_op
+                .as_ref()
+                .is_some_and(|view_props| *view_props != vnode.view_props)
+            {
+                // Its element is created or replaced, and fragments show children elsewhere.
+                if let Some(parent) = inner::host_parent(self, vnode_id) {
+                    inner::record_element_child_v(self, parent);
                 }
 
-                self.on_update_vnode_props(vnode_id, &view_props);
+                inner::record_element_child_v(self, vnode_id);
+            }
 
-                self.get_vnode_mut(&vnode_id).unwrap().view_props = view_props.clone();
+            let vnode = inner::vnode_mut(self, vnode_id).unwrap();
 
-                view_props
-            } else {
-                vnode.view_props.clone()
-            };
+            let view_props = match view_props_op {
+                Some(view_props) if vnode.view_props != view_props => {
+                    let old_class =
+                        std::mem::replace(&mut vnode.view_props, view_props.clone()).class;
 
-            if let Some(inner_props_node) = inner::layout(self, vnode_id, &view_props).await? {
-                if self.get_vnode(&vnode_id).unwrap().inner_id == 0 {
-                    self.get_vnode_mut(&vnode_id).unwrap().inner_id =
-                        self.new_vnode(VNode::new(vnode_id, parent_op));
+                    self.element_op_v_mut().push(ElementOp::Update {
+                        id: vnode_id,
+                        old_class,
+                        view_props: view_props.clone(),
+                    });
+
+                    view_props
                 }
+                _ => vnode.view_props.clone(),
+            };
+
+            let vnode = self.get_vnode(&vnode_id).unwrap();
 
-                let vnode = self.get_vnode(&vnode_id).unwrap();
+            let input = LayoutInput {
+                view_props: view_props.clone(),
+                state: vnode.state.clone(),
+                embeded_child_v: vnode.embeded_child_v.clone(),
+            };
 
-                let inner_id = vnode.inner_id;
-                let embeded_id = vnode.context;
+            if !self.should_update(vnode_id, &input) {
+                log::debug!("skip layout of {vnode_id}");
 
-                inner::apply_inner_props_node(
-                    self,
-                    vnode_id,
-                    inner_id,
-                    &inner_props_node,
-                    embeded_id,
-                )
-                .await;
-            } else if self.get_vnode(&vnode_id).unwrap().inner_id != 0 {
-                let inner_id = self.get_vnode(&vnode_id).unwrap().inner_id;
+                return Ok(());
+            }
+
+            if let Some(inner_props_node) = inner::layout(self, vnode_id, &view_props).await? {
+                let inner_id = match self.get_vnode(&vnode_id).unwrap().inner_id_op {
+                    Some(inner_id) => inner_id,
+                    None => {
+                        inner::record_element_child_v(self, vnode_id);
+
+                        let host_op = self
+                            .journal_mut()
+                            .host_mp
+                            .get(&vnode_id)
+                            .and_then(|host| host.child_v.first().cloned());
+                        let inner_id = inner::create_vnode_at(
+                            self,
+                            VNode::new(vnode_id, parent_op),
+                            host_op.as_ref(),
+                            &inner_props_node.data.class,
+                        );
+
+                        inner::vnode_mut(self, vnode_id).unwrap().inner_id_op = Some(inner_id);
+
+                        inner_id
+                    }
+                };
 
+                inner::apply_inner_props_node(self, vnode_id, inner_id, &inner_props_node).await?;
+
+                inner::vnode_mut(self, vnode_id).unwrap().last_input_op =
+                    inner::is_pure(&inner_props_node).then_some(input);
+            } else if let Some(inner_id) = self.get_vnode(&vnode_id).unwrap().inner_id_op {
+                inner::record_element_child_v(self, vnode_id);
                 inner::remove_node(self, inner_id);
 
-                self.get_vnode_mut(&vnode_id).unwrap().inner_id = 0;
+                let vnode = inner::vnode_mut(self, vnode_id).unwrap();
+
+                vnode.inner_id_op = None;
+                vnode.last_input_op = None;
             }
 
             Ok(())
         })
     }
 
+    /// A class manager over the same classes as `self`, for one layout script to run against
+    /// on its own task, see `execute_layout_v`; `None`, the default, keeps layouts on `self`,
+    /// each run when its vnode is applied.
+    fn fork_class_manager(&self) -> Option<Box<dyn AsClassManager>> {
+        None
+    }
+
+    /// Runs the layout scripts of sibling vnodes, returning their outputs in the same order.
+    ///
+    /// When a tokio runtime is running and `fork_class_manager` gives a class manager for every
+    /// script, the scripts run concurrently, each on a task against its own, and their writes
+    /// are reported to `notify_write` once all are done. Otherwise they run one after another
+    /// against `self`.
+    ///
+    /// The outputs are merged into the vnode tree in vnode order either way; an output that read
+    /// a pair another one wrote is run again when its vnode is applied. An override can run
+    /// scripts with `execute_layout`, which records what each reads and writes.
+    fn execute_layout_v<'a, 'f>(
+        &'a mut self,
+        script_v: Vec<String>,
+    ) -> Pin<Box<dyn Fu<Output = Vec<err::Result<ScriptOutput>>> + 'f>>
+    where
+        'a: 'f,
+        Self: Sized,
+    {
+        Box::pin(async move {
+            let mut rs_v = Vec::with_capacity(script_v.len());
+
+            let fork_v_op = script_v
+                .iter()
+                .map(|_| self.fork_class_manager())
+                .collect::<Option<Vec<Box<dyn AsClassManager>>>>();
+
+            let (fork_v, handle) = match (fork_v_op, tokio::runtime::Handle::try_current()) {
+                (Some(fork_v), Ok(handle)) => (fork_v, handle),
+                _ => {
+                    for script in script_v {
+                        rs_v.push(inner::execute(self, script).await);
+                    }
+
+                    return rs_v;
+                }
+            };
+
+            let join_v = script_v
+                .into_iter()
+                .zip(fork_v)
+                .map(|(script, mut cm)| {
+                    handle.spawn(async move { execute_layout(&mut *cm, script).await })
+                })
+                .collect::<Vec<_>>();
+
+            for join in join_v {
+                rs_v.push(match join.await {
+                    Ok(rs) => rs,
+                    Err(e) => Err(err::Error::RuntimeError)
+                        .attach_printable_lazy(|| format!("layout task failed: {e}")),
+                });
+            }
+
+            // Forks do not go through `self`.
+            for output in rs_v.iter().flatten() {
+                for (class, source) in &output.write_set {
+                    self.notify_write(class, source);
+                }
+            }
+
+            rs_v
+        })
+    }
+
+    /// Lays out like `flush`, then commits against the elements the provider already has, see
+    /// `AsElementProvider::host_tree`, instead of creating every element.
+    ///
+    /// Vnodes are paired with host nodes as they are created, position by position: one of the
+    /// class of its host node takes its id, reserved for it, see `reserve_vnode_id_v`, before
+    /// any layout sees it. `root` is paired when it has the id of the first host node already.
+    /// Children passed to a component are created before their slot is known, so they take the
+    /// id of theirs when first applied, see `inner::settle`. At commit, paired elements are
+    /// adopted, the others reported and replaced.
+    ///
+    /// Fails when an element has the id of a vnode outside `root`.
+    fn hydrate_from<'a, 'f>(
+        &'a mut self,
+        root: u64,
+    ) -> Pin<Box<dyn Fu<Output = err::Result<Vec<Mismatch>>> + 'f>>
+    where
+        'a: 'f,
+        Self: Sized,
+    {
+        Box::pin(async move {
+            let host_v = self.host_tree();
+            let backup = inner::begin(self);
+
+            let id_v = match inner::begin_hydrate(self, root, &host_v) {
+                Ok(r) => r,
+                Err(e) => {
+                    inner::rollback(self, backup);
+
+                    return Err(e);
+                }
+            };
+
+            let rs = match inner::render_until_idle(self, self.max_iteration()).await {
+                Ok(_) => Ok(inner::hydrate(self, root)),
+                Err(e) => {
+                    inner::rollback(self, backup);
+
+                    Err(e)
+                }
+            };
+
+            self.release_vnode_id_v(&id_v);
+
+            rs
+        })
+    }
+
+    /// The vnodes from `root` down, `root` included, that match `selector`, in tree order.
+    ///
+    /// `selector` is like css: classes such as `div` or `Vision:cube3`, `*` for any, `[prop]`
+    /// and `[prop=value]` predicates, and the descendant (` `) and child (`>`) combinators. It
+    /// goes over the host tree: the children of a vnode are the root of its layout when it has
+    /// one, its embeded children otherwise, and fragments are left out.
+    fn query_all_from(&self, root: u64, selector: &str) -> err::Result<Vec<u64>> {
+        inner::selector::query_all(self, root, selector)
+    }
+
+    /// The first vnode `query_all_from` finds.
+    fn query_from(&self, root: u64, selector: &str) -> err::Result<Option<u64>> {
+        Ok(self.query_all_from(root, selector)?.into_iter().next())
+    }
+
+    /// Whether `vnode_id` has to lay out again for `input`.
+    ///
+    /// By default, a view whose root has `$pure` set is skipped while its input stays the same
+    /// as in its last layout.
+    fn should_update(&self, vnode_id: u64, input: &LayoutInput) -> bool {
+        self.get_vnode(&vnode_id).unwrap().last_input_op.as_ref() != Some(input)
+    }
+
+    /// Lays out dirty vnodes until none is left, see `render_until_idle`.
+    fn flush<'a, 'f>(&'a mut self) -> Pin<Box<dyn Fu<Output = err::Result<RenderStat>> + 'f>>
+    where
+        'a: 'f,
+        Self: Sized,
+    {
+        let max_iteration = self.max_iteration();
+
+        self.render_until_idle(max_iteration)
+    }
+
+    /// Times `flush` applies one vnode before it reports a cycle, `DEFAULT_MAX_ITERATION` by
+    /// default.
+    fn max_iteration(&self) -> usize {
+        DEFAULT_MAX_ITERATION
+    }
+
+    /// Lays out dirty vnodes pass after pass until none is left, then commits.
+    ///
+    /// Every pass goes from the shallowest vnodes to the deepest, so a vnode dirtied again by
+    /// an ancestor earlier in the pass is laid out once instead of twice.
+    ///
+    /// Fails when vnodes applied `max_iteration` times are dirty again, naming them, since they
+    /// keep dirtying each other; however deep the tree, other vnodes are applied once a pass.
+    ///
+    /// A flush is all or nothing: when it fails, the vnodes and dirty marks are restored as they
+    /// were and nothing is committed. Classes written by the view scripts are not restored, the
+    /// vnodes that read them are dirty again.
+    fn render_until_idle<'a, 'f>(
+        &'a mut self,
+        max_iteration: usize,
+    ) -> Pin<Box<dyn Fu<Output = err::Result<RenderStat>> + 'f>>
+    where
+        'a: 'f,
+        Self: Sized,
+    {
+        Box::pin(async move {
+            let backup = inner::begin(self);
+
+            match inner::render_until_idle(self, max_iteration).await {
+                Ok(stat) => {
+                    inner::end(self);
+
+                    Ok(stat)
+                }
+                Err(e) => {
+                    inner::rollback(self, backup);
+
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    /// Lays out dirty vnodes like `render_until_idle` but stops once `budget` is spent, after at
+    /// least one vnode.
+    ///
+    /// Every vnode is applied as a whole, so the element tree is consistent when this returns
+    /// and the vnodes left stay dirty for the next slice, see `RenderStat::pending_count`.
+    /// Cycles are not detected, the budget bounds each slice instead.
+    ///
+    /// A failed slice is undone like a failed `render_until_idle`.
+    fn render_slice<'a, 'f>(
+        &'a mut self,
+        budget: Budget,
+    ) -> Pin<Box<dyn Fu<Output = err::Result<RenderStat>> + 'f>>
+    where
+        'a: 'f,
+        Self: Sized,
+    {
+        Box::pin(async move {
+            let backup = inner::begin(self);
+
+            match inner::render_slice(self, &budget).await {
+                Ok(stat) => {
+                    inner::end(self);
+
+                    Ok(stat)
+                }
+                Err(e) => {
+                    inner::rollback(self, backup);
+
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    /// The view script of `class`, stored in the `view` class.
+    ///
+    /// Vnodes of `class` are laid out again when `view` of `class` is written, keeping their
+    /// state, see `notify_write`.
     fn get_class_view<'a, 'a1, 'f>(
         &'a self,
         class: &'a1 str,
@@ -150,13 +693,49 @@ pub trait AsViewManager: AsClassManager + AsElementProvider<H = u64> {
         'a: 'f,
         'a1: 'f;
 
+    /// Records vnodes while a flush is in progress, see `Journal`.
+    fn journal_mut(&mut self) -> &mut Journal;
+
     fn get_vnode(&self, id: &u64) -> Option<&VNode>;
 
     fn get_vnode_mut(&mut self, id: &u64) -> Option<&mut VNode>;
 
+    /// Stores `vnode` under a new id.
+    ///
+    /// The id of a removed vnode must not be handed out again as is, or stale ids would reach
+    /// other vnodes; `VNodeArena` adds a generation to it instead.
     fn new_vnode(&mut self, vnode: VNode) -> u64;
 
     fn rm_vnode(&mut self, id: u64) -> Option<VNode>;
+
+    /// Puts `vnode` back under `id`, to undo a failed flush; fails when `id` is in use.
+    fn insert_vnode(&mut self, id: u64, vnode: VNode) -> err::Result<()>;
+
+    /// Keeps `new_vnode` from handing out the ids in `id_v`, so that hydrated vnodes can take
+    /// them with `new_vnode_at`; ids in use or handed out before are skipped.
+    ///
+    /// The default keeps nothing.
+    fn reserve_vnode_id_v(&mut self, _id_v: &[u64]) {}
+
+    /// Gives back to `new_vnode` the ids of `id_v` that are still reserved.
+    fn release_vnode_id_v(&mut self, _id_v: &[u64]) {}
+
+    /// Stores `vnode` under `id`, reserved by `reserve_vnode_id_v`.
+    ///
+    /// The default fails: hydrated vnodes then get new ids, and their elements are replaced.
+    fn new_vnode_at(&mut self, id: u64, _vnode: VNode) -> err::Result<()> {
+        Err(err::Error::RuntimeError)
+            .attach_printable_lazy(|| format!("can not store a vnode under {id}"))
+    }
+}
+
+/// Runs the layout script `script` against `cm`, recording the (class, source) pairs it reads
+/// and writes, for overrides of `AsViewManager::execute_layout_v`.
+pub async fn execute_layout<CM: AsClassManager + ?Sized>(
+    cm: &mut CM,
+    script: String,
+) -> err::Result<ScriptOutput> {
+    inner::execute(cm, script).await
 }
 
 pub trait AsElementProvider {
@@ -167,4 +746,42 @@ pub trait AsElementProvider {
     fn delete_element(&mut self, id: Self::H);
 
     fn create_element(&mut self, vnode_id: u64, class: &str, props: &json::JsonValue) -> Self::H;
+
+    /// Puts `id` at `index` among the children of `parent`, taking it out of where it is first.
+    ///
+    /// Called at the end of a commit, for created elements and for the children that have to
+    /// move; fragments are skipped, `parent` is the closest vnode with an element. `index`
+    /// counts the children of `parent` as they are at the call, `id` left out.
+    fn move_element(&mut self, _id: Self::H, _parent: Self::H, _index: usize) {}
+
+    /// Attaches the element of the `$portal` `id` under the container named `target` rather
+    /// than under its parent; the children of the portal are then reported with it as parent.
+    ///
+    /// Called whenever the portal is created or its props change.
+    fn attach_portal(&mut self, _id: Self::H, _target: &str) {}
+
+    /// The elements present before the first commit, e.g. rendered by a server, to hydrate.
+    ///
+    /// A provider that starts from scratch has none.
+    fn host_tree(&self) -> Vec<HostNode> {
+        vec![]
+    }
+
+    /// Takes over the existing element `id` for a vnode of `class` with `props` rather than
+    /// creating one, see `host_tree`; the element may have to be updated to `props`.
+    fn adopt_element(&mut self, _id: Self::H, _class: &str, _props: &json::JsonValue) {}
+}
+
+/// A strategy deciding which existing children are reused when a node's children change.
+pub trait AsReconciler: Send + Sync {
+    /// Pairs every child in `new_v` with the child of `old_v` it reuses, `None` for a new vnode.
+    ///
+    /// Returns `(index in new_v, reused id)` in the order the children end up in. Each index of
+    /// `new_v` must appear exactly once and each old id at most once; old children that are not
+    /// reused get removed.
+    fn reconcile(
+        &self,
+        old_v: &[(u64, ViewProps)],
+        new_v: &[ViewProps],
+    ) -> Vec<(usize, Option<u64>)>;
 }
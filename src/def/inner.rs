@@ -1,78 +1,1129 @@
-use std::{collections::BTreeMap, pin::Pin};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    pin::Pin,
+};
 
 use error_stack::ResultExt;
-use moon_class::{def::Fu, executor::ClassExecutor};
+use moon_class::{
+    def::{AsClassManager, Fu},
+    executor::ClassExecutor,
+};
+
+use crate::{
+    bean::{
+        Budget, ElementOp, HostNode, Journal, LayoutInput, Mismatch, RenderStat, ScriptOutput,
+        VNode, ViewProps,
+    },
+    err,
+};
+
+use super::{AsReconciler, AsViewManager};
+
+mod node;
+mod reconciler;
+pub mod selector;
+mod tracker;
+pub mod value;
+
+/// Records how `id` is before the flush in progress changes it.
+fn record<VM: AsViewManager + ?Sized>(vm: &mut VM, id: u64) {
+    if vm.journal_mut().is_open && !vm.journal_mut().origin_mp.contains_key(&id) {
+        let origin = vm.get_vnode(&id).cloned();
+
+        vm.journal_mut().origin_mp.insert(id, origin);
+    }
+}
+
+/// `get_vnode_mut`, recording the vnode first.
+pub fn vnode_mut<VM: AsViewManager + ?Sized>(vm: &mut VM, id: u64) -> Option<&mut VNode> {
+    record(vm, id);
+
+    vm.get_vnode_mut(&id)
+}
+
+/// `new_vnode`, recording that the vnode did not exist.
+pub fn create_vnode(vm: &mut impl AsViewManager, vnode: VNode) -> u64 {
+    let id = vm.new_vnode(vnode);
+
+    if vm.journal_mut().is_open {
+        vm.journal_mut().origin_mp.entry(id).or_insert(None);
+    }
+
+    id
+}
+
+/// Starts recording a flush, returns the dirty vnodes and queue length to restore if it fails.
+pub fn begin(vm: &mut impl AsViewManager) -> (BTreeMap<u64, Option<ViewProps>>, usize) {
+    *vm.journal_mut() = Journal {
+        is_open: true,
+        ..Default::default()
+    };
+
+    (vm.dirty_vnode_v_mut().clone(), vm.element_op_v_mut().len())
+}
+
+/// Ends a flush that succeeded and commits it.
+pub fn end(vm: &mut impl AsViewManager) {
+    queue_move_v(vm, None);
+
+    *vm.journal_mut() = Journal::default();
+
+    vm.commit();
+}
+
+/// The vnodes with elements among `id_v`, fragments replaced by their children.
+fn element_v<VM: AsViewManager + ?Sized>(vm: &VM, id_v: &[u64], element_v: &mut Vec<u64>) {
+    for id in id_v {
+        let vnode = match vm.get_vnode(id) {
+            Some(r) => r,
+            None => {
+                continue;
+            }
+        };
+
+        if vnode.view_props.class == "$fragment" {
+            self::element_v(vm, &vnode.embeded_child_v, element_v);
+        } else {
+            element_v.push(*id);
+        }
+    }
+}
+
+/// The vnode showing `id` in the host tree: the component for the root of its layout, the
+/// parent otherwise.
+pub fn host_parent<VM: AsViewManager + ?Sized>(vm: &VM, id: u64) -> Option<u64> {
+    let vnode = vm.get_vnode(&id)?;
+
+    match vm.get_vnode(&vnode.context) {
+        Some(component) if vnode.context != id && component.inner_id_op == Some(id) => {
+            Some(vnode.context)
+        }
+        _ => vnode.parent_op,
+    }
+}
+
+/// Whether the provider has an element for `vnode`; fragments have none, and new vnodes have
+/// none until they are applied.
+pub fn has_element(vnode: &VNode) -> bool {
+    !vnode.view_props.class.is_empty() && vnode.view_props.class != "$fragment"
+}
+
+/// The vnodes with elements right under the element of `id`.
+pub fn element_child_v<VM: AsViewManager + ?Sized>(vm: &VM, id: u64) -> Vec<u64> {
+    let vnode = vm.get_vnode(&id).unwrap();
+    let child_id_v = match vnode.inner_id_op {
+        Some(inner_id) => vec![inner_id],
+        None => vnode.embeded_child_v.clone(),
+    };
+    let mut element_v = vec![];
+
+    self::element_v(vm, &child_id_v, &mut element_v);
+
+    element_v.retain(|id| has_element(vm.get_vnode(id).unwrap()));
+
+    element_v
+}
+
+/// `root` and the vnodes of the host tree under it, parents first.
+pub fn subtree_id_v<VM: AsViewManager + ?Sized>(vm: &VM, root: u64) -> Vec<u64> {
+    let mut id_v = vec![];
+    let mut stack = vec![root];
+
+    while let Some(id) = stack.pop() {
+        let vnode = match vm.get_vnode(&id) {
+            Some(r) => r,
+            None => {
+                continue;
+            }
+        };
+
+        id_v.push(id);
+
+        match vnode.inner_id_op {
+            Some(inner_id) => stack.push(inner_id),
+            None => stack.extend(vnode.embeded_child_v.iter().rev()),
+        }
+    }
+
+    id_v
+}
+
+/// The vnode whose element holds the element of `id`, past fragments.
+fn element_parent<VM: AsViewManager + ?Sized>(vm: &VM, id: u64) -> Option<u64> {
+    let mut parent = host_parent(vm, id)?;
+
+    while vm.get_vnode(&parent)?.view_props.class == "$fragment" {
+        parent = host_parent(vm, parent)?;
+    }
+
+    Some(parent)
+}
+
+/// Puts the element of `id`, just created again at commit, where the old one was, and its
+/// element children under it.
+///
+/// Runs once the moves of the flush are done, so its siblings are in place already.
+pub fn place<VM: AsViewManager + ?Sized>(vm: &mut VM, id: u64) {
+    // Removed later in the flush.
+    if vm.get_vnode(&id).is_none() {
+        return;
+    }
+
+    if let Some(parent) = element_parent(vm, id) {
+        if let Some(index) = element_child_v(vm, parent).iter().position(|c| *c == id) {
+            vm.move_element(id, parent, index);
+        }
+    }
+
+    for (index, child) in element_child_v(vm, id).into_iter().enumerate() {
+        vm.move_element(child, id, index);
+    }
+}
+
+/// Records the element children of the element holding the children of `id`, before the flush
+/// in progress changes them; see `queue_move_v`.
+pub fn record_element_child_v(vm: &mut impl AsViewManager, id: u64) {
+    if !vm.journal_mut().is_open {
+        return;
+    }
+
+    let mut parent = id;
+
+    // A fragment shows its children in its own parent.
+    while vm
+        .get_vnode(&parent)
+        .is_some_and(|vnode| vnode.view_props.class == "$fragment")
+    {
+        parent = match host_parent(vm, parent) {
+            Some(r) => r,
+            None => {
+                return;
+            }
+        };
+    }
+
+    if vm.get_vnode(&parent).is_none() || vm.journal_mut().element_child_mp.contains_key(&parent) {
+        return;
+    }
+
+    let element_child_v = element_child_v(vm, parent);
+
+    vm.journal_mut()
+        .element_child_mp
+        .insert(parent, element_child_v);
+}
+
+/// Queues the moves that bring the children of the elements recorded by
+/// `record_element_child_v` to their new order; created elements are placed the same way.
+///
+/// The elements that stay in order, the longest increasing run of their old positions, are
+/// left alone; the others are placed from the last to the first, each before its next
+/// sibling. Every `index` is computed against the children as they are when the move runs,
+/// so a child leaving for another parent counts until the move that takes it there.
+///
+/// With `adopted_set_op`, the elements under every parent are the adopted ones, in order.
+fn queue_move_v(vm: &mut impl AsViewManager, adopted_set_op: Option<&BTreeSet<u64>>) {
+    let element_child_mp = std::mem::take(&mut vm.journal_mut().element_child_mp);
+
+    // Changing class, or becoming an element, creates it again.
+    let created_set = vm
+        .element_op_v_mut()
+        .iter()
+        .filter_map(|op| match op {
+            ElementOp::Update {
+                id,
+                old_class,
+                view_props,
+            } if *old_class != view_props.class => Some(*id),
+            _ => None,
+        })
+        .collect::<BTreeSet<u64>>();
+
+    let mut child_mp = BTreeMap::new();
+    let mut current_mp = BTreeMap::new();
+    let mut parent_mp = BTreeMap::new();
+
+    for (parent, old_v) in element_child_mp {
+        if !vm.get_vnode(&parent).is_some_and(has_element) {
+            continue;
+        }
+
+        let child_v = element_child_v(vm, parent);
+        let current_v = match adopted_set_op {
+            Some(adopted_set) => child_v
+                .iter()
+                .filter(|id| adopted_set.contains(id))
+                .copied()
+                .collect::<Vec<u64>>(),
+            None => old_v
+                .into_iter()
+                .filter(|id| !created_set.contains(id) && vm.get_vnode(id).is_some_and(has_element))
+                .collect::<Vec<u64>>(),
+        };
+
+        for id in &current_v {
+            parent_mp.insert(*id, parent);
+        }
+
+        current_mp.insert(parent, current_v);
+        child_mp.insert(parent, child_v);
+    }
+
+    for (parent, child_v) in child_mp {
+        let position_mp = current_mp[&parent]
+            .iter()
+            .enumerate()
+            .map(|(position, id)| (*id, position))
+            .collect::<BTreeMap<u64, usize>>();
+        let kept_v = child_v
+            .iter()
+            .filter_map(|id| position_mp.get(id).map(|position| (*id, *position)))
+            .collect::<Vec<(u64, usize)>>();
+        let stable_set = reconciler::longest_increasing_subsequence(
+            &kept_v
+                .iter()
+                .map(|(_, position)| *position)
+                .collect::<Vec<usize>>(),
+        )
+        .into_iter()
+        .map(|i| kept_v[i].0)
+        .collect::<BTreeSet<u64>>();
+
+        for i in (0..child_v.len()).rev() {
+            let id = child_v[i];
+
+            if stable_set.contains(&id) {
+                continue;
+            }
+
+            if let Some(old_parent) = parent_mp.insert(id, parent) {
+                current_mp
+                    .get_mut(&old_parent)
+                    .unwrap()
+                    .retain(|current_id| *current_id != id);
+            }
+
+            let current_v = current_mp.get_mut(&parent).unwrap();
+            let index = match child_v.get(i + 1) {
+                Some(next) => current_v.iter().position(|id| id == next).unwrap(),
+                None => current_v.len(),
+            };
+
+            current_v.insert(index, id);
+
+            vm.element_op_v_mut()
+                .push(ElementOp::Move { id, parent, index });
+        }
+    }
+}
+
+/// The ids of `host` and every element under it.
+fn host_id_v(host: &HostNode, id_v: &mut Vec<u64>) {
+    id_v.push(host.id);
+
+    for child in &host.child_v {
+        host_id_v(child, id_v);
+    }
+}
+
+/// Pairs the vnodes in `id_v` with the elements in `host_v`, position by position.
+fn pair_host(
+    vm: &impl AsViewManager,
+    id_v: &[u64],
+    host_v: &[HostNode],
+    adopted_set: &mut BTreeSet<u64>,
+    unclaimed_v: &mut Vec<u64>,
+    mismatch_v: &mut Vec<Mismatch>,
+) {
+    for i in 0..id_v.len().max(host_v.len()) {
+        match (id_v.get(i), host_v.get(i)) {
+            (Some(id), Some(host)) => {
+                let vnode = vm.get_vnode(id).unwrap();
+
+                if *id != host.id || vnode.view_props.class != host.class {
+                    mismatch_v.push(Mismatch::Differ {
+                        id: *id,
+                        class: vnode.view_props.class.clone(),
+                        host_id: host.id,
+                        host_class: host.class.clone(),
+                    });
+
+                    host_id_v(host, unclaimed_v);
+
+                    continue;
+                }
+
+                adopted_set.insert(*id);
+
+                let n_id_v = element_child_v(vm, *id);
+
+                pair_host(
+                    vm,
+                    &n_id_v,
+                    &host.child_v,
+                    adopted_set,
+                    unclaimed_v,
+                    mismatch_v,
+                );
+            }
+            (Some(id), None) => mismatch_v.push(Mismatch::Missing {
+                id: *id,
+                class: vm.get_vnode(id).unwrap().view_props.class.clone(),
+            }),
+            (None, Some(host)) => {
+                mismatch_v.push(Mismatch::Extra {
+                    host_id: host.id,
+                    host_class: host.class.clone(),
+                });
+
+                host_id_v(host, unclaimed_v);
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+/// Starts hydrating `root` against `host_v`: reserves the ids of the host tree, and pairs
+/// `root` with the first host node when it has its id and class; see `create_vnode_at`.
+///
+/// Fails when a vnode outside `root` has the id of an element.
+pub fn begin_hydrate(
+    vm: &mut impl AsViewManager,
+    root: u64,
+    host_v: &[HostNode],
+) -> err::Result<Vec<u64>> {
+    let mut id_v = vec![];
+
+    for host in host_v {
+        host_id_v(host, &mut id_v);
+    }
+
+    if let Some(id) = id_v
+        .iter()
+        .find(|id| **id != root && vm.get_vnode(id).is_some())
+    {
+        return Err(err::Error::RuntimeError)
+            .attach_printable_lazy(|| format!("element {id} belongs to another vnode"));
+    }
+
+    vm.reserve_vnode_id_v(&id_v);
+
+    if let Some(host) = host_v.first() {
+        if host.id == root && pending_view_props(vm, root).class == host.class {
+            vm.journal_mut().host_mp.insert(root, host.clone());
+        }
+    }
+
+    Ok(id_v)
+}
+
+/// `create_vnode`, giving the vnode the id of `host_op` when it is of `class` and its id is
+/// still reserved; the children of the vnode are then paired with the children of the host.
+pub fn create_vnode_at(
+    vm: &mut impl AsViewManager,
+    vnode: VNode,
+    host_op: Option<&HostNode>,
+    class: &str,
+) -> u64 {
+    if let Some(host) = host_op.filter(|host| host.class == class) {
+        if vm.new_vnode_at(host.id, vnode.clone()).is_ok() {
+            let journal = vm.journal_mut();
+
+            if journal.is_open {
+                journal.origin_mp.entry(host.id).or_insert(None);
+            }
+
+            journal.host_mp.insert(host.id, host.clone());
+
+            return host.id;
+        }
+    }
+
+    create_vnode(vm, vnode)
+}
+
+/// Gives `id`, a child forwarded through a slot and not applied yet, the id of the host node at
+/// its place in the slot while hydrating; see `create_vnode_at`. Returns its id.
+///
+/// Such a child is created before its slot is known. Until it is applied, its id is only in the
+/// lists of the slot and of the components that forwarded it, no layout has seen it.
+pub async fn settle(
+    vm: &mut impl AsViewManager,
+    id: u64,
+    view_props_op: Option<&ViewProps>,
+) -> u64 {
+    if vm.journal_mut().host_mp.is_empty() || vm.journal_mut().host_mp.contains_key(&id) {
+        return id;
+    }
+
+    let (vnode, view_props) = match (vm.get_vnode(&id), view_props_op) {
+        (Some(vnode), Some(view_props)) if vnode.view_props.class.is_empty() => {
+            (vnode.clone(), view_props)
+        }
+        _ => {
+            return id;
+        }
+    };
+
+    let parent = match vnode.parent_op.and_then(|parent| vm.get_vnode(&parent)) {
+        Some(parent) if parent.context != vnode.context => parent.clone(),
+        _ => {
+            return id;
+        }
+    };
+
+    let host_v = host_child_v(
+        vm,
+        parent.context,
+        vnode.parent_op.unwrap(),
+        &parent.view_props,
+    )
+    .await;
+    let host = match parent
+        .embeded_child_v
+        .iter()
+        .position(|child_id| *child_id == id)
+        .and_then(|position| host_v.get(position))
+    {
+        Some(host) if host.class == view_props.class => host.clone(),
+        _ => {
+            return id;
+        }
+    };
+
+    if vm.new_vnode_at(host.id, vnode.clone()).is_err() {
+        return id;
+    }
+
+    record(vm, id);
+    vm.rm_vnode(id);
+
+    let journal = vm.journal_mut();
+
+    journal.origin_mp.entry(host.id).or_insert(None);
+    journal.host_mp.insert(host.id, host.clone());
+
+    // The slot, then the components that forwarded it, back to the one it was passed to.
+    let mut lister_op = vnode.parent_op;
+
+    while let Some(lister) = lister_op {
+        let lister_vnode = match vnode_mut(vm, lister) {
+            Some(r) => r,
+            None => {
+                break;
+            }
+        };
+
+        for child_id in &mut lister_vnode.embeded_child_v {
+            if *child_id == id {
+                *child_id = host.id;
+            }
+        }
+
+        lister_op = (lister_vnode.context != vnode.context && lister_vnode.context != lister)
+            .then_some(lister_vnode.context);
+    }
+
+    for child_id in &vnode.embeded_child_v {
+        if let Some(child) = vnode_mut(vm, *child_id) {
+            child.parent_op = Some(host.id);
+        }
+    }
+
+    if let Some(view_props_op) = vm.dirty_vnode_v_mut().remove(&id) {
+        vm.dirty_vnode_v_mut().insert(host.id, view_props_op);
+    }
+
+    host.id
+}
+
+/// The host nodes the children laid out under `vnode_id` with `view_props` are paired with
+/// while hydrating, position by position.
+///
+/// Those of an element are under its host node; those of a fragment, the root of a layout,
+/// are under the host node of the component. The children of a component go to slots, whose
+/// place is not known before its layout; they are paired once forwarded, see `settle`.
+async fn host_child_v(
+    vm: &mut impl AsViewManager,
+    context: u64,
+    vnode_id: u64,
+    view_props: &ViewProps,
+) -> Vec<HostNode> {
+    if vm.journal_mut().host_mp.is_empty() {
+        return vec![];
+    }
+
+    let host_id = if view_props.class == "$fragment" {
+        match vm.get_vnode(&context) {
+            Some(component) if component.inner_id_op == Some(vnode_id) => context,
+            _ => {
+                return vec![];
+            }
+        }
+    } else if vm.get_class_view(&view_props.class).await.is_some() {
+        return vec![];
+    } else {
+        vnode_id
+    };
+
+    vm.journal_mut()
+        .host_mp
+        .get(&host_id)
+        .map_or(vec![], |host| host.child_v.clone())
+}
+
+/// Ends a flush that succeeded by committing it against the host tree, see `hydrate_from`.
+///
+/// Created elements that are in the host tree are adopted instead; host elements without a
+/// vnode are deleted first.
+pub fn hydrate(vm: &mut impl AsViewManager, root: u64) -> Vec<Mismatch> {
+    let host_v = vm.host_tree();
+    let mut id_v = vec![];
+    let mut adopted_set = BTreeSet::new();
+    let mut unclaimed_v = vec![];
+    let mut mismatch_v = vec![];
+
+    element_v(vm, &[root], &mut id_v);
+    pair_host(
+        vm,
+        &id_v,
+        &host_v,
+        &mut adopted_set,
+        &mut unclaimed_v,
+        &mut mismatch_v,
+    );
+
+    // Adopted elements are in place already.
+    queue_move_v(vm, Some(&adopted_set));
+
+    *vm.journal_mut() = Journal::default();
+
+    let mut op_v = unclaimed_v
+        .into_iter()
+        .map(|id| ElementOp::Delete { id })
+        .collect::<Vec<ElementOp>>();
+
+    for op in std::mem::take(vm.element_op_v_mut()) {
+        match op {
+            ElementOp::Update {
+                id,
+                old_class,
+                view_props,
+            } if old_class.is_empty() && adopted_set.contains(&id) => {
+                op_v.push(ElementOp::Adopt { id, view_props });
+            }
+            op => op_v.push(op),
+        }
+    }
+
+    *vm.element_op_v_mut() = op_v;
+
+    vm.commit();
+
+    mismatch_v
+}
+
+/// Restores everything a failed flush changed, dropping the element operations it queued.
+pub fn rollback(
+    vm: &mut impl AsViewManager,
+    (dirty_vnode_v, op_count): (BTreeMap<u64, Option<ViewProps>>, usize),
+) {
+    let journal = std::mem::take(vm.journal_mut());
+    let origin_mp = journal.origin_mp;
+
+    // All out first, a slot may be reused by a vnode of the flush.
+    for id in origin_mp.keys() {
+        if let Some(vnode) = vm.rm_vnode(*id) {
+            forget_read_set(vm, *id, &vnode.read_set);
+        }
+    }
+
+    for (id, origin) in origin_mp
+        .into_iter()
+        .filter_map(|(id, origin_op)| Some((id, origin_op?)))
+    {
+        for pair in &origin.read_set {
+            vm.dep_mp_mut().entry(pair.clone()).or_default().insert(id);
+        }
+
+        if let Err(e) = vm.insert_vnode(id, origin) {
+            log::error!("rollback: {e:?}");
+        }
+    }
+
+    *vm.dirty_vnode_v_mut() = dirty_vnode_v;
+
+    vm.element_op_v_mut().truncate(op_count);
+
+    // Writes to classes stay.
+    for (class, source) in journal.write_set {
+        vm.notify_write(&class, &source);
+    }
+}
+
+/// Removes the embeded children of `vnode_id` from `n_sz` on.
+///
+/// Children forwarded into a slot belong to another layout, they are only detached.
+pub fn trunc_embeded(vnode_id: u64, vm: &mut impl AsViewManager, n_sz: usize) {
+    let vnode = match vnode_mut(vm, vnode_id) {
+        Some(r) => r,
+        None => {
+            return;
+        }
+    };
+
+    let context = vnode.context;
+
+    for id in vnode.embeded_child_v.split_off(n_sz) {
+        if is_owned(vm, context, id) {
+            remove_node(vm, id);
+        } else {
+            detach(vm, context, vnode_id, id);
+        }
+    }
+}
+
+/// Gives the child `id`, forwarded into `vnode_id` by the component `context`, back to it.
+fn detach(vm: &mut impl AsViewManager, context: u64, vnode_id: u64, id: u64) {
+    if vm
+        .get_vnode(&id)
+        .is_some_and(|vnode| vnode.parent_op == Some(vnode_id))
+    {
+        vnode_mut(vm, id).unwrap().parent_op = Some(context);
+    }
+}
+
+/// How many ancestors `id` has in the host tree, so the root of a layout is deeper than its
+/// component.
+pub fn depth(vm: &impl AsViewManager, id: u64) -> usize {
+    let mut depth = 0;
+    let mut parent_op = host_parent(vm, id);
+
+    while let Some(parent) = parent_op {
+        depth += 1;
+        parent_op = host_parent(vm, parent);
+    }
+
+    depth
+}
+
+/// Whether `id` was created by the layout of `context` rather than forwarded into it.
+fn is_owned(vm: &impl AsViewManager, context: u64, id: u64) -> bool {
+    vm.get_vnode(&id)
+        .is_some_and(|vnode| vnode.context == context)
+}
+
+/// The props `id` is going to be laid out with, pending ones first.
+fn pending_view_props(vm: &mut impl AsViewManager, id: u64) -> ViewProps {
+    match vm.dirty_vnode_v_mut().get(&id) {
+        Some(Some(view_props)) => view_props.clone(),
+        _ => vm.get_vnode(&id).unwrap().view_props.clone(),
+    }
+}
+
+/// The embeded children of the component `context` that go to `slot`.
+fn slot_child_v(vm: &mut impl AsViewManager, context: u64, slot: &str) -> Vec<u64> {
+    let embeded_child_v = vm.get_vnode(&context).unwrap().embeded_child_v.clone();
+
+    let mut slot_child_v = vec![];
+
+    for id in embeded_child_v {
+        let view_props = pending_view_props(vm, id);
+        let child_slot = view_props.prop("$slot");
+
+        let is_in_slot = if child_slot.is_null() {
+            slot.is_empty()
+        } else {
+            *child_slot == *slot
+        };
+
+        if is_in_slot {
+            slot_child_v.push(id);
+        }
+    }
+
+    slot_child_v
+}
+
+pub fn remove_node(vm: &mut impl AsViewManager, id: u64) {
+    trunc_embeded(id, vm, 0);
+
+    // A forwarded child is still listed by the slot it was forwarded into.
+    if let Some(parent) = vm.get_vnode(&id).and_then(|vnode| vnode.parent_op) {
+        if vm
+            .get_vnode(&parent)
+            .is_some_and(|vnode| vnode.embeded_child_v.contains(&id))
+        {
+            vnode_mut(vm, parent)
+                .unwrap()
+                .embeded_child_v
+                .retain(|child_id| *child_id != id);
+        }
+    }
+
+    vm.dirty_vnode_v_mut().remove(&id);
+
+    let inner_id_op = match vm.get_vnode(&id) {
+        Some(r) => r,
+        None => {
+            return;
+        }
+    }
+    .inner_id_op;
+
+    if let Some(inner_id) = inner_id_op {
+        remove_node(vm, inner_id);
+    }
+
+    if vm.get_vnode(&id).unwrap().view_props.class != "$fragment" {
+        vm.element_op_v_mut().push(ElementOp::Delete { id });
+    }
+
+    record(vm, id);
+
+    let read_set = vm.rm_vnode(id).unwrap().read_set;
+
+    forget_read_set(vm, id, &read_set);
+}
+
+/// Takes `id` out of the vnodes depending on the pairs of `read_set`, see `dep_mp_mut`.
+fn forget_read_set(vm: &mut impl AsViewManager, id: u64, read_set: &BTreeSet<(String, String)>) {
+    for pair in read_set {
+        if let Some(id_set) = vm.dep_mp_mut().get_mut(pair) {
+            id_set.remove(&id);
+
+            if id_set.is_empty() {
+                vm.dep_mp_mut().remove(pair);
+            }
+        }
+    }
+}
+
+/// The whole layout script of `vnode_id` with `view_props`, `None` when its class has no view.
+async fn layout_script(
+    vm: &mut impl AsViewManager,
+    vnode_id: u64,
+    view_props: &ViewProps,
+) -> err::Result<Option<String>> {
+    let rs = if let Some(script) = vm.get_class_view(&view_props.class).await {
+        let state = &vm
+            .get_vnode(&vnode_id)
+            .ok_or(err::Error::NotFound)
+            .attach_printable_lazy(|| format!("vnode with id {vnode_id} not found!"))?
+            .state;
+
+        let pre_script = format!(
+            r#"{state} = $state();
+{} = $props();
+{vnode_id} = $vnode_id();
+"#,
+            view_props.props
+        );
+
+        Some(format!("{pre_script}{script}"))
+    } else {
+        None
+    };
+
+    Ok(rs)
+}
+
+///
+pub async fn layout(
+    vm: &mut impl AsViewManager,
+    vnode_id: u64,
+    view_props: &ViewProps,
+) -> err::Result<Option<node::Node<ViewProps>>> {
+    let (rs, mut read_set) = match layout_script(vm, vnode_id, view_props).await? {
+        Some(script) => {
+            let scope = json::object! {
+                state: vm.get_vnode(&vnode_id).unwrap().state.clone(),
+                props: view_props.props.clone(),
+            };
+
+            // Run ahead with its siblings, unless its input changed since.
+            let output = match vm.journal_mut().layout_mp.remove(&vnode_id) {
+                Some((ahead_script, output)) if ahead_script == script => output,
+                // Its writes go through `vm`, which reports them.
+                _ => execute(vm, script).await?,
+            };
+
+            (Some(node::parse(&output.root, &scope)?), output.read_set)
+        }
+        None => (None, BTreeSet::new()),
+    };
+
+    // Lay out again when its view is defined or redefined.
+    read_set.insert(("view".to_string(), view_props.class.clone()));
+
+    for pair in &read_set {
+        vm.dep_mp_mut()
+            .entry(pair.clone())
+            .or_default()
+            .insert(vnode_id);
+    }
+
+    if vm.get_vnode(&vnode_id).unwrap().read_set != read_set {
+        let old_read_set =
+            std::mem::replace(&mut vnode_mut(vm, vnode_id).unwrap().read_set, read_set);
+        let unread_set = old_read_set
+            .difference(&vm.get_vnode(&vnode_id).unwrap().read_set)
+            .cloned()
+            .collect::<BTreeSet<(String, String)>>();
+
+        forget_read_set(vm, vnode_id, &unread_set);
+    }
+
+    Ok(rs)
+}
+
+/// Runs the layouts of the vnodes in `job_v` together through `execute_layout_v`, keeping the
+/// results for `layout`.
+async fn layout_ahead(
+    vm: &mut impl AsViewManager,
+    job_v: Vec<(u64, Option<ViewProps>)>,
+) -> err::Result<()> {
+    let mut id_v = vec![];
+    let mut script_v = vec![];
+
+    for (vnode_id, view_props_op) in job_v {
+        let vnode = match vm.get_vnode(&vnode_id) {
+            Some(r) => r,
+            None => {
+                continue;
+            }
+        };
+
+        let view_props = view_props_op.unwrap_or_else(|| vnode.view_props.clone());
+        let input = LayoutInput {
+            view_props: view_props.clone(),
+            state: vnode.state.clone(),
+            embeded_child_v: vnode.embeded_child_v.clone(),
+        };
+
+        if !vm.should_update(vnode_id, &input) {
+            continue;
+        }
+
+        if let Some(script) = layout_script(vm, vnode_id, &view_props).await? {
+            id_v.push(vnode_id);
+            script_v.push(script);
+        }
+    }
 
-use crate::{
-    bean::{VNode, ViewProps},
-    err,
-};
+    // Nothing to run side by side.
+    if script_v.len() < 2 {
+        return Ok(());
+    }
 
-use super::AsViewManager;
+    let rs_v = vm.execute_layout_v(script_v.clone()).await;
+    let mut write_v = vec![];
 
-mod node;
+    for ((vnode_id, script), rs) in id_v.into_iter().zip(script_v).zip(rs_v) {
+        // A failure is met again, and reported, when the vnode is applied.
+        if let Ok(output) = rs {
+            for pair in &output.write_set {
+                write_v.push((vnode_id, pair.clone()));
+            }
 
-pub fn trunc_embeded(vnode_id: u64, vm: &mut impl AsViewManager, n_sz: usize) {
-    let embeded_child_v = &mut match vm.get_vnode_mut(&vnode_id) {
-        Some(r) => r,
-        None => {
-            return;
+            vm.journal_mut()
+                .layout_mp
+                .insert(vnode_id, (script, output));
         }
     }
-    .embeded_child_v;
 
-    for id in embeded_child_v.split_off(n_sz) {
-        remove_node(vm, id);
+    // Whether a sibling saw the write depends on timing, it is run again when applied instead.
+    for (vnode_id, pair) in write_v {
+        drop_ahead(vm, &pair, Some(vnode_id));
     }
+
+    Ok(())
 }
 
-pub fn remove_node(vm: &mut impl AsViewManager, id: u64) {
-    trunc_embeded(id, vm, 0);
+/// Runs a layout script against `cm`, tracking what it reads and writes; see
+/// `AsViewManager::execute_layout_v`.
+pub async fn execute<CM: AsClassManager + ?Sized>(
+    cm: &mut CM,
+    script: String,
+) -> err::Result<ScriptOutput> {
+    let mut tracker = tracker::Tracker::new(cm);
 
-    let inner_id = match vm.get_vnode(&id) {
-        Some(r) => r,
-        None => {
-            return;
+    let root = node::execute(script, &mut tracker).await?;
+
+    Ok(ScriptOutput {
+        root,
+        read_set: tracker.read_set.into_inner().unwrap(),
+        write_set: tracker.write_set,
+    })
+}
+
+/// Drops the layouts run ahead that read `pair`, but the one of `except_op`: they may have
+/// missed a write to it.
+pub fn drop_ahead(
+    vm: &mut (impl AsViewManager + ?Sized),
+    pair: &(String, String),
+    except_op: Option<u64>,
+) {
+    vm.journal_mut()
+        .layout_mp
+        .retain(|id, (_, output)| Some(*id) == except_op || !output.read_set.contains(pair));
+}
+
+/// Applies the vnodes dirty when it starts, shallowest first, until `budget` is spent; the ones
+/// left are marked dirty again.
+///
+/// Returns whether the pass got to its end.
+pub async fn render_pass(
+    vm: &mut impl AsViewManager,
+    budget: &Budget,
+    stat: &mut RenderStat,
+) -> err::Result<bool> {
+    let mut dirty_vnode_v = std::mem::take(vm.dirty_vnode_v_mut())
+        .into_iter()
+        .map(|(vnode_id, view_props_op)| (depth(vm, vnode_id), vnode_id, view_props_op))
+        .collect::<Vec<(usize, u64, Option<ViewProps>)>>();
+
+    // Popped from the back.
+    dirty_vnode_v.sort_by_key(|(depth, vnode_id, _)| Reverse((*depth, *vnode_id)));
+
+    let mut ahead_depth_op = None;
+    // Without forks, layouts run ahead would run one after another all the same.
+    let is_concurrent =
+        tokio::runtime::Handle::try_current().is_ok() && vm.fork_class_manager().is_some();
+
+    while let Some((depth, vnode_id, mut view_props_op)) = dirty_vnode_v.pop() {
+        if stat.apply_count > 0 && budget.is_spent(stat) {
+            dirty_vnode_v.push((depth, vnode_id, view_props_op));
+
+            for (_, vnode_id, view_props_op) in dirty_vnode_v {
+                if vm.get_vnode(&vnode_id).is_none() {
+                    continue;
+                }
+
+                // Marks made during the pass are newer.
+                let entry = vm.dirty_vnode_v_mut().entry(vnode_id).or_insert(None);
+
+                if entry.is_none() {
+                    *entry = view_props_op;
+                }
+            }
+
+            return Ok(false);
         }
-    }
-    .inner_id;
 
-    if inner_id != 0 {
-        remove_node(vm, inner_id);
+        // Removed by an ancestor.
+        if vm.get_vnode(&vnode_id).is_none() {
+            stat.saved_count += 1;
+
+            continue;
+        }
+
+        // Dirtied again by an ancestor.
+        if let Some(n_view_props_op) = vm.dirty_vnode_v_mut().remove(&vnode_id) {
+            stat.saved_count += 1;
+
+            if n_view_props_op.is_some() {
+                view_props_op = n_view_props_op;
+            }
+        }
+
+        // Siblings do not depend on each other, their layouts can run together; not against a
+        // deadline, which could pass before their turn.
+        if is_concurrent && budget.deadline_op.is_none() && ahead_depth_op != Some(depth) {
+            ahead_depth_op = Some(depth);
+
+            let max_count = budget
+                .max_apply_op
+                .map_or(usize::MAX, |max| max.saturating_sub(stat.apply_count));
+            let mut job_v = vec![(vnode_id, view_props_op.clone())];
+
+            for (_, id, op) in dirty_vnode_v
+                .iter()
+                .rev()
+                .take_while(|(n_depth, _, _)| *n_depth == depth)
+                .take(max_count.saturating_sub(1))
+            {
+                let op = match vm.dirty_vnode_v_mut().get(id) {
+                    Some(Some(view_props)) => Some(view_props.clone()),
+                    _ => op.clone(),
+                };
+
+                job_v.push((*id, op));
+            }
+
+            layout_ahead(vm, job_v).await?;
+        }
+
+        vm.apply_props(vnode_id, view_props_op).await?;
+
+        stat.apply_count += 1;
+        *vm.journal_mut().apply_count_mp.entry(vnode_id).or_default() += 1;
     }
 
-    vm.delete_element(id);
-    vm.rm_vnode(id);
+    Ok(true)
 }
 
-///
-pub async fn layout(
+/// Lays out dirty vnodes pass after pass until none is left, see `render_until_idle`.
+pub async fn render_until_idle(
     vm: &mut impl AsViewManager,
-    vnode_id: u64,
-    view_props: &ViewProps,
-) -> err::Result<Option<node::Node<ViewProps>>> {
-    let rs = if let Some(script) = vm.get_class_view(&view_props.class).await {
-        let state = vm
-            .get_vnode(&vnode_id)
-            .ok_or(err::Error::NotFound)
-            .attach_printable_lazy(|| format!("vnode with id {vnode_id} not found!"))?
-            .state
-            .clone();
+    max_iteration: usize,
+) -> err::Result<RenderStat> {
+    let mut stat = RenderStat::default();
 
-        let pre_script = format!(
-            r#"{state} = $state();
-{} = $props();
-{vnode_id} = $vnode_id();
-"#,
-            view_props.props
-        );
+    while !vm.dirty_vnode_v_mut().is_empty() {
+        let id_v = vm.dirty_vnode_v_mut().keys().copied().collect::<Vec<u64>>();
+        let mut cycle_v = vec![];
 
-        Some(node::execute_as_node(format!("{pre_script}{script}"), vm).await)
-    } else {
-        None
-    };
+        // Those applied as often as allowed are dirty again.
+        for id in id_v {
+            if vm
+                .journal_mut()
+                .apply_count_mp
+                .get(&id)
+                .is_some_and(|count| *count >= max_iteration)
+            {
+                let class = vm
+                    .get_vnode(&id)
+                    .map_or("", |vnode| vnode.view_props.class.as_str());
 
-    Ok(rs)
+                cycle_v.push(format!("{id}({class})"));
+            }
+        }
+
+        if !cycle_v.is_empty() {
+            return Err(err::Error::RuntimeError).attach_printable_lazy(|| {
+                format!(
+                    "still dirty after {max_iteration} layouts: {}",
+                    cycle_v.join(", ")
+                )
+            });
+        }
+
+        render_pass(vm, &Budget::default(), &mut stat).await?;
+    }
+
+    Ok(stat)
+}
+
+/// Lays out dirty vnodes until none is left or `budget` is spent, see `render_slice`.
+pub async fn render_slice(vm: &mut impl AsViewManager, budget: &Budget) -> err::Result<RenderStat> {
+    let mut stat = RenderStat::default();
+
+    while !vm.dirty_vnode_v_mut().is_empty() {
+        if !render_pass(vm, budget, &mut stat).await? {
+            break;
+        }
+    }
+
+    stat.pending_count = vm.dirty_vnode_v_mut().len();
+
+    Ok(stat)
+}
+
+/// Whether the view that produced `node` asked to be skipped while its input is unchanged.
+pub fn is_pure(node: &node::Node<ViewProps>) -> bool {
+    value::is_truthy(node.data.prop("$pure"))
 }
 
 pub async fn event_handler(
@@ -92,126 +1143,510 @@ pub async fn event_handler(
 
     log::debug!("event_handler: script = {script}");
 
-    let mut ce = ClassExecutor::new(vm);
-
-    ce.execute_script(&script)
+    // Its writes go through `vm`, which reports them.
+    ClassExecutor::new(vm)
+        .execute_script(&script)
         .await
         .change_context(err::Error::RuntimeError)?;
 
     Ok(())
 }
 
+/// A position among the children of a node once its `$child` placeholders are resolved.
+enum Entry<'n> {
+    /// A child laid out by this node.
+    Node(&'n node::Node<ViewProps>),
+    /// A child of the component forwarded through a slot.
+    Forwarded(u64),
+}
+
+/// Resolves the placeholders in `child_v` against the embeded children of `context` and
+/// flattens fragments into their parent.
+fn expand_child_v<'n>(
+    vm: &mut impl AsViewManager,
+    context: u64,
+    child_v: &'n [node::Node<ViewProps>],
+    entry_v: &mut Vec<Entry<'n>>,
+) {
+    for child in child_v {
+        match node::slot_name(&child.data.class) {
+            Some(slot) => {
+                let slot_child_v = slot_child_v(vm, context, slot);
+
+                if slot_child_v.is_empty() {
+                    // A slot nobody fills shows the placeholder's own children.
+                    expand_child_v(vm, context, &child.child_v, entry_v);
+                } else {
+                    entry_v.extend(slot_child_v.into_iter().map(Entry::Forwarded));
+                }
+            }
+            None if child.data.class == "$fragment" => {
+                expand_child_v(vm, context, &child.child_v, entry_v);
+            }
+            None => entry_v.push(Entry::Node(child)),
+        }
+    }
+}
+
+/// Lets the reconciler of `node_type` pair the nodes in `entry_v` with the children of
+/// `vnode_id` created by `context`, then creates, removes and places vnodes as decided.
+///
+/// Returns the vnodes to lay out with their nodes.
+fn reconcile<'n>(
+    vm: &mut impl AsViewManager,
+    context: u64,
+    vnode_id: u64,
+    node_type: &str,
+    entry_v: Vec<Entry<'n>>,
+    host_v: &[HostNode],
+) -> err::Result<Vec<(u64, &'n node::Node<ViewProps>)>> {
+    record_element_child_v(vm, vnode_id);
+
+    let old_child_v = vm.get_vnode(&vnode_id).unwrap().embeded_child_v.clone();
+
+    let mut old_v = vec![];
+
+    for id in &old_child_v {
+        if is_owned(vm, context, *id) {
+            old_v.push((*id, pending_view_props(vm, *id)));
+        }
+    }
+
+    let node_v = entry_v
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::Node(node) => Some(*node),
+            Entry::Forwarded(_) => None,
+        })
+        .collect::<Vec<&node::Node<ViewProps>>>();
+    let new_v = node_v
+        .iter()
+        .map(|node| node.data.clone())
+        .collect::<Vec<ViewProps>>();
+
+    let pair_v = match vm.reconciler_mp_mut().get(node_type) {
+        Some(reconciler) => reconciler.reconcile(&old_v, &new_v),
+        None => match node_type {
+            "list" => reconciler::ListReconciler.reconcile(&old_v, &new_v),
+            "set" => reconciler::SetReconciler.reconcile(&old_v, &new_v),
+            "ordered-set" => reconciler::OrderedSetReconciler.reconcile(&old_v, &new_v),
+            _ => {
+                return Err(err::Error::NotFound)
+                    .attach_printable_lazy(|| format!("no reconciler for type {node_type}"));
+            }
+        },
+    };
+
+    let mut index_set = BTreeSet::new();
+    let mut reused_set = BTreeSet::new();
+
+    for (i, id_op) in &pair_v {
+        let is_valid_id = match id_op {
+            Some(id) => old_v.iter().any(|(old_id, _)| old_id == id) && reused_set.insert(*id),
+            None => true,
+        };
+
+        if *i >= new_v.len() || !index_set.insert(*i) || !is_valid_id {
+            return Err(err::Error::RuntimeError).attach_printable_lazy(|| {
+                format!("reconciler {node_type} paired child {i} with {id_op:?} unexpectedly")
+            });
+        }
+    }
+
+    if index_set.len() != new_v.len() {
+        return Err(err::Error::RuntimeError)
+            .attach_printable_lazy(|| format!("reconciler {node_type} dropped some children"));
+    }
+
+    let mut pair_iter = pair_v.into_iter();
+    let mut embeded_child_v = Vec::with_capacity(entry_v.len());
+    let mut laid_out_v = Vec::with_capacity(node_v.len());
+
+    for (position, entry) in entry_v.into_iter().enumerate() {
+        match entry {
+            Entry::Node(_) => {
+                let (i, id_op) = pair_iter.next().unwrap();
+                let id = match id_op {
+                    Some(id) => id,
+                    None => create_vnode_at(
+                        vm,
+                        VNode::new(context, Some(vnode_id)),
+                        host_v.get(position),
+                        &node_v[i].data.class,
+                    ),
+                };
+
+                embeded_child_v.push(id);
+                laid_out_v.push((id, node_v[i]));
+            }
+            Entry::Forwarded(id) => {
+                vnode_mut(vm, id).unwrap().parent_op = Some(vnode_id);
+
+                embeded_child_v.push(id);
+            }
+        }
+    }
+
+    for id in &old_child_v {
+        if !embeded_child_v.contains(id) && !is_owned(vm, context, *id) {
+            detach(vm, context, vnode_id, *id);
+        }
+    }
+
+    vnode_mut(vm, vnode_id).unwrap().embeded_child_v = embeded_child_v;
+
+    for (id, _) in &old_v {
+        if !reused_set.contains(id) {
+            remove_node(vm, *id);
+        }
+    }
+
+    Ok(laid_out_v)
+}
+
 pub fn apply_inner_props_node<'a, 'a1, 'f>(
     vm: &'a mut impl AsViewManager,
     context: u64,
     vnode_id: u64,
     view_props_node: &'a1 node::Node<ViewProps>,
-    embeded_id: u64,
-) -> Pin<Box<dyn Fu<Output = ()> + 'f>>
+) -> Pin<Box<dyn Fu<Output = err::Result<()>> + 'f>>
 where
     'a: 'f,
     'a1: 'f,
 {
     Box::pin(async move {
-        if !view_props_node.child_v.is_empty() && view_props_node.child_v[0].data.class == "$child"
-        {
-            trunc_embeded(vnode_id, vm, 0);
+        let old_child_v = vm.get_vnode(&vnode_id).unwrap().embeded_child_v.clone();
+        let node_type = view_props_node.data.props["$type"][0]
+            .as_str()
+            .unwrap_or("list");
 
-            let embeded_child_v = vm.get_vnode(&embeded_id).unwrap().embeded_child_v.clone();
+        let mut entry_v = vec![];
 
-            for id in &embeded_child_v {
-                vm.get_vnode_mut(id).unwrap().parent_op = Some(vnode_id);
-            }
+        expand_child_v(vm, context, &view_props_node.child_v, &mut entry_v);
 
-            vm.get_vnode_mut(&vnode_id).unwrap().embeded_child_v = embeded_child_v;
-        } else {
-            let node_type = view_props_node.data.props["$type"][0]
-                .as_str()
-                .unwrap_or("list");
-
-            match node_type {
-                "set" => {
-                    let mut embeded_child_mp = BTreeMap::new();
-
-                    for id in &vm.get_vnode(&vnode_id).unwrap().embeded_child_v {
-                        embeded_child_mp.insert(vm.get_vnode(id).unwrap().view_props.clone(), *id);
-                    }
-
-                    for node in &view_props_node.child_v {
-                        match embeded_child_mp.remove(&node.data) {
-                            Some(id) => {
-                                apply_inner_props_node(vm, context, id, node, embeded_id).await
-                            }
-                            None => {
-                                let new_id = vm.new_vnode(VNode::new(context, Some(vnode_id)));
-                                vm.get_vnode_mut(&vnode_id)
-                                    .unwrap()
-                                    .embeded_child_v
-                                    .push(new_id);
-
-                                apply_inner_props_node(vm, context, new_id, node, embeded_id).await
-                            }
-                        }
-                    }
-
-                    let embeded_child_v = &mut vm.get_vnode_mut(&vnode_id).unwrap().embeded_child_v;
-
-                    embeded_child_v.sort();
-
-                    for (_, id) in &embeded_child_mp {
-                        let index = embeded_child_v.binary_search(id).unwrap();
-                        embeded_child_v.remove(index);
-                    }
-
-                    for (_, id) in &embeded_child_mp {
-                        remove_node(vm, *id);
-                    }
-                }
-                "list" => {
-                    let diff = view_props_node.child_v.len() as i32
-                        - vm.get_vnode(&vnode_id).unwrap().embeded_child_v.len() as i32;
-                    if diff > 0 {
-                        let new_id_v = (0..diff)
-                            .into_iter()
-                            .map(|_| vm.new_vnode(VNode::new(context, Some(vnode_id))))
-                            .collect::<Vec<u64>>();
-
-                        vm.get_vnode_mut(&vnode_id)
-                            .unwrap()
-                            .embeded_child_v
-                            .extend(new_id_v);
-                    } else {
-                        trunc_embeded(vnode_id, vm, view_props_node.child_v.len());
-                    }
-
-                    for i in 0..view_props_node.child_v.len() {
-                        let child_id = *vm
-                            .get_vnode(&vnode_id)
-                            .unwrap()
-                            .embeded_child_v
-                            .get(i)
-                            .unwrap();
-
-                        apply_inner_props_node(
-                            vm,
-                            context,
-                            child_id,
-                            &view_props_node.child_v[i],
-                            embeded_id,
-                        )
-                        .await;
-                    }
-                }
-                _ => todo!(),
-            }
+        let host_v = host_child_v(vm, context, vnode_id, &view_props_node.data).await;
+
+        for (id, node) in reconcile(vm, context, vnode_id, node_type, entry_v, &host_v)? {
+            apply_inner_props_node(vm, context, id, node).await?;
         }
 
-        let vnode = vm.get_vnode_mut(&vnode_id).unwrap();
+        let vnode = vm.get_vnode(&vnode_id).unwrap();
 
-        if vnode.view_props != view_props_node.data {
-            vnode.is_dirty = true;
-            vm.dirty_vnode_v_mut()
-                .insert(vnode_id, Some(view_props_node.data.clone()));
+        // A component has to lay out again to forward its new children.
+        if vnode.inner_id_op.is_some() && vnode.embeded_child_v != old_child_v {
+            vm.mark_dirty(vnode_id, None);
         }
+
+        if vm.get_vnode(&vnode_id).unwrap().view_props != view_props_node.data {
+            vm.mark_dirty(vnode_id, Some(view_props_node.data.clone()));
+        }
+
+        Ok(())
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use moon_class::ClassManager;
+
+    use crate::{def::AsElementProvider, manager::StandardViewManager};
+
+    use super::*;
+
+    struct NoProvider;
+
+    impl AsElementProvider for NoProvider {
+        type H = u64;
+
+        fn reuse_element(&mut self, _id: u64, _class: &str, _props: &json::JsonValue) -> bool {
+            true
+        }
+
+        fn delete_element(&mut self, _id: u64) {}
+
+        fn create_element(&mut self, vnode_id: u64, _class: &str, _props: &json::JsonValue) -> u64 {
+            vnode_id
+        }
+    }
+
+    /// Keeps the element tree, and never reuses an element.
+    #[derive(Default)]
+    struct TreeProvider {
+        child_mp: BTreeMap<u64, Vec<u64>>,
+    }
+
+    impl TreeProvider {
+        fn detach(&mut self, id: u64) {
+            for child_v in self.child_mp.values_mut() {
+                child_v.retain(|child| *child != id);
+            }
+        }
+    }
+
+    impl AsElementProvider for TreeProvider {
+        type H = u64;
+
+        fn reuse_element(&mut self, _id: u64, _class: &str, _props: &json::JsonValue) -> bool {
+            false
+        }
+
+        fn delete_element(&mut self, id: u64) {
+            self.detach(id);
+            self.child_mp.remove(&id);
+        }
+
+        fn create_element(&mut self, vnode_id: u64, _class: &str, _props: &json::JsonValue) -> u64 {
+            vnode_id
+        }
+
+        fn move_element(&mut self, id: u64, parent: u64, index: usize) {
+            self.detach(id);
+
+            let child_v = self.child_mp.entry(parent).or_default();
+
+            assert!(index <= child_v.len());
+
+            child_v.insert(index, id);
+        }
+    }
+
+    /// Has the elements of `host_v` already, and records what is done to its elements.
+    #[derive(Default)]
+    struct HostProvider {
+        host_v: Vec<HostNode>,
+        log_v: Vec<String>,
+    }
+
+    impl AsElementProvider for HostProvider {
+        type H = u64;
+
+        fn reuse_element(&mut self, _id: u64, _class: &str, _props: &json::JsonValue) -> bool {
+            true
+        }
+
+        fn delete_element(&mut self, id: u64) {
+            self.log_v.push(format!("delete {id}"));
+        }
+
+        fn create_element(&mut self, vnode_id: u64, _class: &str, _props: &json::JsonValue) -> u64 {
+            self.log_v.push(format!("create {vnode_id}"));
+
+            vnode_id
+        }
+
+        fn host_tree(&self) -> Vec<HostNode> {
+            self.host_v.clone()
+        }
+
+        fn adopt_element(&mut self, id: u64, _class: &str, _props: &json::JsonValue) {
+            self.log_v.push(format!("adopt {id}"));
+        }
+    }
+
+    fn new_element(vm: &mut impl AsViewManager, class: &str, parent_op: Option<u64>) -> u64 {
+        let id = create_vnode(vm, VNode::new(0, parent_op));
+        let vnode = vm.get_vnode_mut(&id).unwrap();
+
+        vnode.view_props.class = class.to_string();
+        vnode.context = parent_op.unwrap_or(id);
+
+        if let Some(parent) = parent_op {
+            vm.get_vnode_mut(&parent).unwrap().embeded_child_v.push(id);
+        }
+
+        id
+    }
+
+    #[test]
+    fn test_queue_move_v() {
+        let mut vm = StandardViewManager::new(ClassManager::new(), TreeProvider::default());
+
+        let p = new_element(&mut vm, "div", None);
+        let a = new_element(&mut vm, "span", Some(p));
+        let b = new_element(&mut vm, "span", Some(p));
+        let c = new_element(&mut vm, "span", Some(p));
+        let x = new_element(&mut vm, "i", Some(a));
+
+        vm.ep_mut().child_mp.insert(p, vec![a, b, c]);
+        vm.ep_mut().child_mp.insert(a, vec![x]);
+
+        begin(&mut vm);
+        record_element_child_v(&mut vm, p);
+
+        // `d` is created, `c` moves to the front and `a` only changes props.
+        let d = create_vnode(&mut vm, VNode::new(p, Some(p)));
+        let view_props = ViewProps {
+            class: "span".to_string(),
+            props: json::object! { v: 1 },
+        };
+
+        vm.get_vnode_mut(&d).unwrap().view_props = view_props.clone();
+        vm.get_vnode_mut(&p).unwrap().embeded_child_v = vec![c, d, a, b];
+        vm.get_vnode_mut(&a).unwrap().view_props = view_props.clone();
+        vm.element_op_v_mut().push(ElementOp::Update {
+            id: d,
+            old_class: String::new(),
+            view_props: view_props.clone(),
+        });
+        vm.element_op_v_mut().push(ElementOp::Update {
+            id: a,
+            old_class: "span".to_string(),
+            view_props,
+        });
+
+        end(&mut vm);
+
+        // `a` was created again, in its place and with its children.
+        assert_eq!(vm.ep().child_mp[&p], vec![c, d, a, b]);
+        assert_eq!(vm.ep().child_mp[&a], vec![x]);
+    }
+
+    #[tokio::test]
+    async fn test_hydrate() {
+        let host = |id: u64, class: &str, child_v: Vec<HostNode>| HostNode {
+            id,
+            class: class.to_string(),
+            child_v,
+        };
+        let view_props = |class: &str| ViewProps {
+            class: class.to_string(),
+            props: json::Null,
+        };
+
+        let mut vm = StandardViewManager::new(ClassManager::new(), HostProvider::default());
+
+        let root = vm.new_vnode(VNode::new(0, None));
+
+        vm.get_vnode_mut(&root).unwrap().context = root;
+        vm.get_vnode_mut(&root).unwrap().view_props = view_props("section");
+
+        // `root` holds the component `Box`, whose layout is a `div` showing the `span` passed to
+        // the component.
+        let host_v = vec![host(
+            root,
+            "section",
+            vec![host(
+                5,
+                "Box",
+                vec![host(6, "div", vec![host(7, "span", vec![])])],
+            )],
+        )];
+
+        vm.ep_mut().host_v = host_v.clone();
+
+        begin(&mut vm);
+
+        assert_eq!(begin_hydrate(&mut vm, root, &host_v).unwrap().len(), 4);
+
+        let host_op = vm.journal_mut().host_mp[&root].child_v.first().cloned();
+        let component = create_vnode_at(
+            &mut vm,
+            VNode::new(root, Some(root)),
+            host_op.as_ref(),
+            "Box",
+        );
+
+        assert_eq!(component, 5);
+
+        let host_op = vm.journal_mut().host_mp[&component]
+            .child_v
+            .first()
+            .cloned();
+        let inner_root = create_vnode_at(
+            &mut vm,
+            VNode::new(component, Some(root)),
+            host_op.as_ref(),
+            "div",
+        );
+
+        assert_eq!(inner_root, 6);
+
+        // Created with a new id, not yet knowing it goes to the `div`.
+        let child = create_vnode(&mut vm, VNode::new(root, Some(inner_root)));
+
+        assert!(child < 5);
+
+        vm.get_vnode_mut(&root).unwrap().embeded_child_v = vec![component];
+        vm.get_vnode_mut(&component).unwrap().embeded_child_v = vec![child];
+        vm.get_vnode_mut(&component).unwrap().inner_id_op = Some(inner_root);
+        vm.get_vnode_mut(&inner_root).unwrap().embeded_child_v = vec![child];
+
+        let child = settle(&mut vm, child, Some(&view_props("span"))).await;
+
+        assert_eq!(child, 7);
+        assert_eq!(vm.get_vnode(&component).unwrap().embeded_child_v, vec![7]);
+        assert_eq!(vm.get_vnode(&inner_root).unwrap().embeded_child_v, vec![7]);
+        assert_eq!(vm.get_vnode(&7).unwrap().parent_op, Some(inner_root));
+
+        for (id, class) in [(root, "section"), (5, "Box"), (6, "div"), (7, "span")] {
+            vm.get_vnode_mut(&id).unwrap().view_props = view_props(class);
+            vm.element_op_v_mut().push(ElementOp::Update {
+                id,
+                old_class: String::new(),
+                view_props: view_props(class),
+            });
+        }
+
+        assert!(hydrate(&mut vm, root).is_empty());
+        assert_eq!(
+            vm.ep().log_v,
+            vec![
+                format!("adopt {root}"),
+                "adopt 5".to_string(),
+                "adopt 6".to_string(),
+                "adopt 7".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rollback_keeps_write() {
+        let mut vm = StandardViewManager::new(ClassManager::new(), NoProvider);
+
+        let pair = ("data".to_string(), "k".to_string());
+        let a = vm.new_vnode(VNode::new(0, None));
+
+        vm.get_vnode_mut(&a).unwrap().read_set.insert(pair.clone());
+        vm.dep_mp_mut().entry(pair).or_default().insert(a);
+
+        let backup = begin(&mut vm);
+
+        vm.notify_write("data", "k");
+
+        rollback(&mut vm, backup);
+
+        // The write was not undone, `a` has to see it.
+        assert!(vm.dirty_vnode_v_mut().contains_key(&a));
+        assert!(vm.get_vnode(&a).unwrap().is_dirty);
+    }
+
+    #[test]
+    fn test_rollback_after_slot_reuse() {
+        let mut vm = StandardViewManager::new(ClassManager::new(), NoProvider);
+
+        let a = vm.new_vnode(VNode::new(0, None));
+
+        vm.get_vnode_mut(&a).unwrap().view_props.class = "div".to_string();
+
+        let backup = begin(&mut vm);
+
+        remove_node(&mut vm, a);
+
+        let b = create_vnode(&mut vm, VNode::new(0, None));
+
+        // The slot of `a` is reused by `b`.
+        assert_eq!(a & u32::MAX as u64, b & u32::MAX as u64);
+        assert!(vm.get_vnode(&a).is_none());
+
+        rollback(&mut vm, backup);
+
+        assert_eq!(vm.get_vnode(&a).unwrap().view_props.class, "div");
+        assert!(vm.get_vnode(&b).is_none());
+        assert!(vm.element_op_v_mut().is_empty());
+
+        // The slot is taken by `a` again.
+        let c = vm.new_vnode(VNode::new(0, None));
+
+        assert_ne!(a & u32::MAX as u64, c & u32::MAX as u64);
+    }
+}
@@ -1,40 +1,160 @@
-use moon_class::executor::{def::AsClassManagerHolder, ClassExecutor};
+use error_stack::ResultExt;
+use moon_class::{
+    def::AsClassManager,
+    executor::{def::AsClassManagerHolder, ClassExecutor},
+};
 
-use crate::{bean::ViewProps, def::AsViewManager};
+use crate::{bean::ViewProps, err};
 
 mod inner {
     use error_stack::ResultExt;
-    use moon_class::err;
 
-    use crate::bean::ViewProps;
+    use crate::{bean::ViewProps, def::inner::value, err};
 
     use super::Node;
 
-    pub fn parse_child(root: &json::JsonValue) -> Node<ViewProps> {
-        if root.is_string() && root.as_str().unwrap() == "$child" {
-            return Node::new(ViewProps {
-                class: format!("$child"),
+    /// Evaluates `$if` in `scope`: a path into it, negated by a leading `!`.
+    ///
+    /// A string not starting with a name of `scope`, like `true` or `0`, is a literal instead.
+    fn test(condition: &json::JsonValue, scope: &json::JsonValue) -> err::Result<bool> {
+        match condition.as_str() {
+            Some(expr) => match expr.trim().strip_prefix('!') {
+                Some(expr) => Ok(!eval(expr.trim(), scope)?),
+                None => eval(expr.trim(), scope),
+            },
+            None => Ok(condition.is_null() || value::is_truthy(condition)),
+        }
+    }
+
+    /// Whether the value at `expr` in `scope`, or `expr` itself when it is no path into it,
+    /// counts as true.
+    fn eval(expr: &str, scope: &json::JsonValue) -> err::Result<bool> {
+        let name = expr.split(['.', '[']).next().unwrap_or_default();
+
+        if scope.has_key(name) {
+            Ok(value::is_truthy(value::get(scope, expr)?))
+        } else {
+            Ok(value::is_truthy(&expr.into()))
+        }
+    }
+
+    /// Replaces every `"{{path}}"` string in `root` with the value at `path` in `scope`.
+    fn bind(root: &json::JsonValue, scope: &json::JsonValue) -> err::Result<json::JsonValue> {
+        if let Some(path) = root
+            .as_str()
+            .and_then(|s| s.strip_prefix("{{"))
+            .and_then(|s| s.strip_suffix("}}"))
+        {
+            return Ok(value::get(scope, path.trim())?.clone());
+        }
+
+        match root {
+            json::JsonValue::Array(item_v) => Ok(json::JsonValue::Array(
+                item_v
+                    .iter()
+                    .map(|item| bind(item, scope))
+                    .collect::<err::Result<Vec<json::JsonValue>>>()?,
+            )),
+            json::JsonValue::Object(object) => {
+                let mut rs = json::object! {};
+
+                for (key, item) in object.iter() {
+                    rs[key] = bind(item, scope)?;
+                }
+
+                Ok(rs)
+            }
+            _ => Ok(root.clone()),
+        }
+    }
+
+    /// Parses `root` into `node_v`, expanding `$for` and `$if` in `scope`.
+    ///
+    /// `$for: { in: path, as: name, index: name }` repeats `root` for every item at `path`,
+    /// `$if` is tested for each item. The item reaches `$props` through bindings, strings like
+    /// `"{{name.key}}"` replaced by the value at their path.
+    pub fn parse_child(
+        root: &json::JsonValue,
+        scope: &json::JsonValue,
+        node_v: &mut Vec<Node<ViewProps>>,
+    ) -> err::Result<()> {
+        if let Some(class) = root
+            .as_str()
+            .filter(|class| super::slot_name(class).is_some())
+        {
+            node_v.push(Node::new(ViewProps {
+                class: class.to_string(),
                 props: json::Null,
-            });
+            }));
+
+            return Ok(());
+        }
+
+        let for_ = &root["$for"][0];
+
+        if !for_.is_null() {
+            let path = for_["in"][0]
+                .as_str()
+                .ok_or(err::Error::RuntimeError)
+                .attach_printable_lazy(|| format!("$for = {for_} without in"))?;
+            let name = for_["as"][0].as_str().unwrap_or("item");
+            let index_name_op = for_["index"][0].as_str();
+
+            let mut root = root.clone();
+
+            root.remove("$for");
+
+            for (i, item) in value::get(scope, path)?.members().enumerate() {
+                let mut scope = scope.clone();
+
+                scope[name] = item.clone();
+
+                if let Some(index_name) = index_name_op {
+                    scope[index_name] = i.into();
+                }
+
+                parse_child(&root, &scope, node_v)?;
+            }
+
+            return Ok(());
         }
-        Node::new_with_child_v(
+
+        if !test(&root["$if"][0], scope)? {
+            return Ok(());
+        }
+
+        let class = root["$class"][0]
+            .as_str()
+            .ok_or(err::Error::RuntimeError)
+            .attach_printable_lazy(|| format!("root = {root}, root[$class] = {}", root["$class"]))?
+            .to_string();
+
+        let mut child_v = vec![];
+
+        for child in root["$child"].members() {
+            parse_child(child, scope, &mut child_v)?;
+        }
+
+        node_v.push(Node::new_with_child_v(
             ViewProps {
-                class: root["$class"][0]
-                    .as_str()
-                    .ok_or(err::Error::RuntimeError)
-                    .attach_printable_lazy(|| {
-                        format!("root = {root}, root[$class] = {}", root["$class"])
-                    })
-                    .unwrap()
-                    .to_string(),
-                props: root["$props"][0].clone(),
+                class,
+                props: bind(&root["$props"][0], scope)?,
             },
-            root["$child"]
-                .members()
-                .into_iter()
-                .map(|child| parse_child(child))
-                .collect(),
-        )
+            child_v,
+        ));
+
+        Ok(())
+    }
+}
+
+/// The slot a `$child` placeholder refers to, "" for the default one.
+///
+/// `$child` takes the children without a `$slot`, `$child:header` the ones with `$slot: header`.
+pub fn slot_name(class: &str) -> Option<&str> {
+    match class.strip_prefix("$child") {
+        Some("") => Some(""),
+        Some(name) => name.strip_prefix(':'),
+        None => None,
     }
 }
 
@@ -65,18 +185,82 @@ impl<Data> Node<Data> {
     }
 }
 
-pub async fn execute_as_node(script: String, vm: &mut impl AsViewManager) -> Node<ViewProps> {
-    log::debug!("execute_as_node: script = {script}");
+/// Runs a view script, returning its result dumped.
+pub async fn execute(script: String, cm: &mut impl AsClassManager) -> err::Result<json::JsonValue> {
+    log::debug!("execute: script = {script}");
 
-    let mut ce = ClassExecutor::new(vm);
+    let mut ce = ClassExecutor::new(cm);
 
-    let rs = ce.execute_script(&script).await.unwrap();
+    let rs = ce
+        .execute_script(&script)
+        .await
+        .change_context(err::Error::RuntimeError)?;
 
-    log::debug!("execute_as_node: root = {}", rs[0]);
+    log::debug!("execute: root = {}", rs[0]);
 
     let root = ce.dump(&rs[0]).await;
 
-    log::debug!("execute_as_node: {root}");
+    log::debug!("execute: {root}");
+
+    Ok(root)
+}
+
+/// Parses the result of a view script, `scope` feeds the `$if` and `$for` directives.
+///
+/// A result that expands to other than one node is wrapped in a fragment.
+pub fn parse(root: &json::JsonValue, scope: &json::JsonValue) -> err::Result<Node<ViewProps>> {
+    let mut node_v = vec![];
+
+    inner::parse_child(root, scope, &mut node_v)?;
 
-    inner::parse_child(&root)
+    if node_v.len() == 1 {
+        Ok(node_v.pop().unwrap())
+    } else {
+        Ok(Node::new_with_child_v(
+            ViewProps {
+                class: "$fragment".to_string(),
+                props: json::Null,
+            },
+            node_v,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let root = json::object! {
+            "$class": ["div"],
+            "$child": [
+                { "$if": ["true"], "$class": ["a"] },
+                { "$if": ["state.open"], "$class": ["b"] },
+                { "$if": ["!0"], "$class": ["c"] },
+                {
+                    "$for": [{ "in": ["state.items"], "as": ["item"] }],
+                    "$if": ["item.shown"],
+                    "$class": ["d"],
+                    "$props": [{ "$key": "{{item.key}}" }]
+                }
+            ]
+        };
+        let scope = json::object! {
+            state: {
+                items: [{ key: "x", shown: true }, { key: "y" }]
+            }
+        };
+
+        let node = parse(&root, &scope).unwrap();
+
+        let class_v = node
+            .child_v
+            .iter()
+            .map(|child| child.data.class.as_str())
+            .collect::<Vec<&str>>();
+
+        assert_eq!(class_v, vec!["a", "c", "d"]);
+        assert_eq!(node.child_v[2].data.props["$key"], "x");
+    }
 }
@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{bean::ViewProps, def::AsReconciler};
+
+/// Identity of a "list" child: its `$key` prop, or its position when it has none.
+fn child_key(view_props: &ViewProps, index: usize) -> String {
+    let key = view_props.prop("$key");
+
+    if key.is_null() {
+        format!("$index:{index}")
+    } else {
+        format!("$key:{key}")
+    }
+}
+
+/// Positions in `seq` of one of its longest strictly increasing subsequences.
+pub fn longest_increasing_subsequence(seq: &[usize]) -> Vec<usize> {
+    let mut tail_v: Vec<usize> = vec![];
+    let mut prev_v = vec![None; seq.len()];
+
+    for (i, n) in seq.iter().enumerate() {
+        let j = tail_v.partition_point(|pos| seq[*pos] < *n);
+
+        if j > 0 {
+            prev_v[i] = Some(tail_v[j - 1]);
+        }
+
+        if j == tail_v.len() {
+            tail_v.push(i);
+        } else {
+            tail_v[j] = i;
+        }
+    }
+
+    let mut pos_v = vec![];
+    let mut pos_op = tail_v.last().copied();
+
+    while let Some(pos) = pos_op {
+        pos_v.push(pos);
+        pos_op = prev_v[pos];
+    }
+
+    pos_v.reverse();
+    pos_v
+}
+
+/// Matches children by `$key`, falling back to their position, and keeps the script's order.
+pub struct ListReconciler;
+
+impl AsReconciler for ListReconciler {
+    fn reconcile(
+        &self,
+        old_v: &[(u64, ViewProps)],
+        new_v: &[ViewProps],
+    ) -> Vec<(usize, Option<u64>)> {
+        let mut old_mp = BTreeMap::new();
+
+        for (i, (id, view_props)) in old_v.iter().enumerate() {
+            old_mp.entry(child_key(view_props, i)).or_insert(*id);
+        }
+
+        let mut key_set = BTreeSet::new();
+
+        new_v
+            .iter()
+            .enumerate()
+            .map(|(i, view_props)| {
+                let key = child_key(view_props, i);
+
+                if !key_set.insert(key.clone()) {
+                    log::warn!("duplicate key {key}");
+                }
+
+                (i, old_mp.remove(&key))
+            })
+            .collect()
+    }
+}
+
+/// Matches children by their whole `ViewProps` and keeps the script's order.
+pub struct OrderedSetReconciler;
+
+impl AsReconciler for OrderedSetReconciler {
+    fn reconcile(
+        &self,
+        old_v: &[(u64, ViewProps)],
+        new_v: &[ViewProps],
+    ) -> Vec<(usize, Option<u64>)> {
+        let mut old_mp = BTreeMap::new();
+
+        for (id, view_props) in old_v {
+            old_mp.entry(view_props).or_insert(*id);
+        }
+
+        new_v
+            .iter()
+            .enumerate()
+            .map(|(i, view_props)| (i, old_mp.remove(view_props)))
+            .collect()
+    }
+}
+
+/// Matches children by their whole `ViewProps`, reused children are ordered by vnode id and
+/// followed by the new ones.
+pub struct SetReconciler;
+
+impl AsReconciler for SetReconciler {
+    fn reconcile(
+        &self,
+        old_v: &[(u64, ViewProps)],
+        new_v: &[ViewProps],
+    ) -> Vec<(usize, Option<u64>)> {
+        let mut pair_v = OrderedSetReconciler.reconcile(old_v, new_v);
+
+        pair_v.sort_by_key(|(_, id_op)| (id_op.is_none(), *id_op));
+
+        pair_v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `pos_v` picks an increasing run of `seq`.
+    fn is_increasing(seq: &[usize], pos_v: &[usize]) -> bool {
+        pos_v
+            .windows(2)
+            .all(|w| w[0] < w[1] && seq[w[0]] < seq[w[1]])
+    }
+
+    #[test]
+    fn test_longest_increasing_subsequence() {
+        assert!(longest_increasing_subsequence(&[]).is_empty());
+        assert_eq!(longest_increasing_subsequence(&[0, 1, 2]), vec![0, 1, 2]);
+        assert_eq!(longest_increasing_subsequence(&[2, 1, 0]).len(), 1);
+        assert_eq!(longest_increasing_subsequence(&[3, 0, 1, 2]), vec![1, 2, 3]);
+
+        let seq = [4, 0, 5, 1, 6, 2, 3];
+        let pos_v = longest_increasing_subsequence(&seq);
+
+        assert_eq!(pos_v.len(), 4);
+        assert!(is_increasing(&seq, &pos_v));
+    }
+}
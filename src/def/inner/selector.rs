@@ -0,0 +1,294 @@
+use std::iter::Peekable;
+
+use error_stack::ResultExt;
+
+use crate::{bean::ViewProps, def::AsViewManager, err};
+
+/// How a compound relates to the one before it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Combinator {
+    Descendant,
+    Child,
+}
+
+/// A class, or any with `*`, and prop predicates, like `Box[$key=3]`.
+pub struct Compound {
+    class_op: Option<String>,
+    /// `[prop]` tests that the prop is set, `[prop=value]` that it shows as `value`.
+    predicate_v: Vec<(String, Option<String>)>,
+}
+
+impl Compound {
+    fn is_match(&self, view_props: &ViewProps) -> bool {
+        if let Some(class) = &self.class_op {
+            if *class != view_props.class {
+                return false;
+            }
+        }
+
+        self.predicate_v.iter().all(|(name, value_op)| {
+            let prop = view_props.prop(name);
+
+            match value_op {
+                Some(value) => match prop.as_str() {
+                    Some(s) => s == value,
+                    None => !prop.is_null() && prop.dump() == *value,
+                },
+                None => !prop.is_null(),
+            }
+        })
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    !c.is_whitespace() && !"[]>=\"'".contains(c)
+}
+
+fn take_name(char_iter: &mut Peekable<impl Iterator<Item = char>>) -> String {
+    let mut name = String::new();
+
+    while let Some(c) = char_iter.next_if(|c| is_name_char(*c)) {
+        name.push(c);
+    }
+
+    name
+}
+
+fn parse_compound(
+    char_iter: &mut Peekable<impl Iterator<Item = char>>,
+    selector: &str,
+) -> err::Result<Compound> {
+    let name = take_name(char_iter);
+    let mut predicate_v = vec![];
+
+    while char_iter.next_if_eq(&'[').is_some() {
+        let prop = take_name(char_iter);
+
+        let value_op = if char_iter.next_if_eq(&'=').is_some() {
+            let mut value = String::new();
+
+            match char_iter.next_if(|c| *c == '"' || *c == '\'') {
+                Some(quote) => loop {
+                    match char_iter.next() {
+                        Some(c) if c == quote => break,
+                        Some(c) => value.push(c),
+                        None => {
+                            return Err(err::Error::RuntimeError).attach_printable_lazy(|| {
+                                format!("unclosed quote in selector {selector}")
+                            });
+                        }
+                    }
+                },
+                None => value = take_name(char_iter),
+            }
+
+            Some(value)
+        } else {
+            None
+        };
+
+        if prop.is_empty() || char_iter.next_if_eq(&']').is_none() {
+            return Err(err::Error::RuntimeError)
+                .attach_printable_lazy(|| format!("invalid predicate in selector {selector}"));
+        }
+
+        predicate_v.push((prop, value_op));
+    }
+
+    if name.is_empty() && predicate_v.is_empty() {
+        return Err(err::Error::RuntimeError)
+            .attach_printable_lazy(|| format!("unexpected character in selector {selector}"));
+    }
+
+    Ok(Compound {
+        class_op: Some(name).filter(|name| !name.is_empty() && name != "*"),
+        predicate_v,
+    })
+}
+
+/// Parses `selector` into compounds with the combinator before each.
+pub fn parse(selector: &str) -> err::Result<Vec<(Combinator, Compound)>> {
+    let mut char_iter = selector.chars().peekable();
+    let mut part_v = vec![];
+    let mut combinator_op = None;
+
+    loop {
+        while char_iter.next_if(|c| c.is_whitespace()).is_some() {}
+
+        match char_iter.peek() {
+            None => break,
+            Some('>') => {
+                if part_v.is_empty() || combinator_op.is_some() {
+                    return Err(err::Error::RuntimeError)
+                        .attach_printable_lazy(|| format!("misplaced > in selector {selector}"));
+                }
+
+                char_iter.next();
+                combinator_op = Some(Combinator::Child);
+            }
+            Some(_) => {
+                let compound = parse_compound(&mut char_iter, selector)?;
+
+                part_v.push((
+                    combinator_op.take().unwrap_or(Combinator::Descendant),
+                    compound,
+                ));
+            }
+        }
+    }
+
+    if part_v.is_empty() || combinator_op.is_some() {
+        return Err(err::Error::RuntimeError)
+            .attach_printable_lazy(|| format!("incomplete selector {selector}"));
+    }
+
+    Ok(part_v)
+}
+
+/// Whether `id`, under `ancestor_v` from the root down, matches `part_v`.
+fn is_match<VM: AsViewManager + ?Sized>(
+    vm: &VM,
+    part_v: &[(Combinator, Compound)],
+    id: u64,
+    ancestor_v: &[u64],
+) -> bool {
+    let ((combinator, compound), rest) = match part_v.split_last() {
+        Some(r) => r,
+        None => {
+            return true;
+        }
+    };
+
+    if !vm
+        .get_vnode(&id)
+        .is_some_and(|vnode| compound.is_match(&vnode.view_props))
+    {
+        return false;
+    }
+
+    if rest.is_empty() {
+        return true;
+    }
+
+    match combinator {
+        Combinator::Child => ancestor_v
+            .split_last()
+            .is_some_and(|(parent, up_v)| is_match(vm, rest, *parent, up_v)),
+        Combinator::Descendant => (0..ancestor_v.len())
+            .rev()
+            .any(|i| is_match(vm, rest, ancestor_v[i], &ancestor_v[..i])),
+    }
+}
+
+/// Collects the vnodes from `id` down that match `part_v`, in tree order.
+fn walk<VM: AsViewManager + ?Sized>(
+    vm: &VM,
+    part_v: &[(Combinator, Compound)],
+    id: u64,
+    ancestor_v: &mut Vec<u64>,
+    id_v: &mut Vec<u64>,
+) {
+    let vnode = match vm.get_vnode(&id) {
+        Some(r) => r,
+        None => {
+            return;
+        }
+    };
+
+    // A fragment only groups its children, they count as children of its parent.
+    let is_fragment = vnode.view_props.class == "$fragment";
+
+    if !is_fragment {
+        if is_match(vm, part_v, id, ancestor_v) {
+            id_v.push(id);
+        }
+
+        ancestor_v.push(id);
+    }
+
+    match vnode.inner_id_op {
+        Some(inner_id) => walk(vm, part_v, inner_id, ancestor_v, id_v),
+        None => {
+            for child_id in &vnode.embeded_child_v {
+                walk(vm, part_v, *child_id, ancestor_v, id_v);
+            }
+        }
+    }
+
+    if !is_fragment {
+        ancestor_v.pop();
+    }
+}
+
+/// The vnodes from `root` down that match `selector`, in tree order.
+pub fn query_all<VM: AsViewManager + ?Sized>(
+    vm: &VM,
+    root: u64,
+    selector: &str,
+) -> err::Result<Vec<u64>> {
+    let part_v = parse(selector)?;
+    let mut id_v = vec![];
+
+    walk(vm, &part_v, root, &mut vec![], &mut id_v);
+
+    Ok(id_v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let part_v = parse("div > Box[$key=3] *[x='a b'][y]").unwrap();
+
+        assert!(
+            part_v.iter().map(|(c, _)| *c).collect::<Vec<Combinator>>()
+                == [
+                    Combinator::Descendant,
+                    Combinator::Child,
+                    Combinator::Descendant
+                ]
+        );
+        assert_eq!(part_v[0].1.class_op.as_deref(), Some("div"));
+        assert_eq!(part_v[1].1.class_op.as_deref(), Some("Box"));
+        assert_eq!(
+            part_v[1].1.predicate_v,
+            vec![("$key".to_string(), Some("3".to_string()))]
+        );
+        assert_eq!(part_v[2].1.class_op, None);
+        assert_eq!(
+            part_v[2].1.predicate_v,
+            vec![
+                ("x".to_string(), Some("a b".to_string())),
+                ("y".to_string(), None)
+            ]
+        );
+
+        for selector in ["", "> div", "div >", "div > > p", "div[", "div[x='a]", "[]"] {
+            assert!(parse(selector).is_err(), "{selector}");
+        }
+    }
+
+    #[test]
+    fn test_is_match() {
+        let view_props = ViewProps {
+            class: "Box".to_string(),
+            props: json::object! { "$key": [3], name: ["a b"] },
+        };
+
+        for (selector, is_match) in [
+            ("Box", true),
+            ("*[$key=3]", true),
+            ("Box[name='a b']", true),
+            ("Box[name]", true),
+            ("Box[other]", false),
+            ("div", false),
+            ("Box[$key=4]", false),
+        ] {
+            let part_v = parse(selector).unwrap();
+
+            assert_eq!(part_v[0].1.is_match(&view_props), is_match, "{selector}");
+        }
+    }
+}
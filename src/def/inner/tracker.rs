@@ -0,0 +1,74 @@
+use std::{collections::BTreeSet, pin::Pin, sync::Mutex};
+
+use moon_class::def::{AsClassManager, Fu};
+
+/// Passes class access through to `cm`, recording the (class, source) pairs read and written.
+pub struct Tracker<'cm, CM: ?Sized> {
+    cm: &'cm mut CM,
+    pub read_set: Mutex<BTreeSet<(String, String)>>,
+    pub write_set: BTreeSet<(String, String)>,
+}
+
+impl<'cm, CM: AsClassManager + ?Sized> Tracker<'cm, CM> {
+    pub fn new(cm: &'cm mut CM) -> Self {
+        Self {
+            cm,
+            read_set: Mutex::new(BTreeSet::new()),
+            write_set: BTreeSet::new(),
+        }
+    }
+}
+
+impl<'cm, CM: AsClassManager + ?Sized> AsClassManager for Tracker<'cm, CM> {
+    fn remove<'a, 'a1, 'a2, 'f>(
+        &'a mut self,
+        class: &'a1 str,
+        source: &'a2 str,
+        target_v: Vec<String>,
+    ) -> Pin<Box<dyn Fu<Output = moon_class::err::Result<()>> + 'f>>
+    where
+        'a: 'f,
+        'a1: 'f,
+        'a2: 'f,
+    {
+        self.write_set
+            .insert((class.to_string(), source.to_string()));
+
+        self.cm.remove(class, source, target_v)
+    }
+
+    fn get<'a, 'a1, 'a2, 'f>(
+        &'a self,
+        class: &'a1 str,
+        source: &'a2 str,
+    ) -> Pin<Box<dyn Fu<Output = moon_class::err::Result<Vec<String>>> + 'f>>
+    where
+        'a: 'f,
+        'a1: 'f,
+        'a2: 'f,
+    {
+        self.read_set
+            .lock()
+            .unwrap()
+            .insert((class.to_string(), source.to_string()));
+
+        self.cm.get(class, source)
+    }
+
+    fn append<'a, 'a1, 'a2, 'f>(
+        &'a mut self,
+        class: &'a1 str,
+        source: &'a2 str,
+        item_v: Vec<String>,
+    ) -> Pin<Box<dyn Fu<Output = moon_class::err::Result<()>> + 'f>>
+    where
+        'a: 'f,
+        'a1: 'f,
+        'a2: 'f,
+    {
+        self.write_set
+            .insert((class.to_string(), source.to_string()));
+
+        self.cm.append(class, source, item_v)
+    }
+}
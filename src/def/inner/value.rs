@@ -0,0 +1,174 @@
+use error_stack::ResultExt;
+
+use crate::err;
+
+/// A step of a path like `a.b[2]`.
+pub enum Segment<'p> {
+    Key(&'p str),
+    Index(usize),
+}
+
+pub fn parse_path(path: &str) -> err::Result<Vec<Segment<'_>>> {
+    let mut segment_v = vec![];
+
+    for part in path.split('.') {
+        let (key, mut rest) = part.split_at(part.find('[').unwrap_or(part.len()));
+
+        if key.is_empty() && rest.is_empty() {
+            return Err(err::Error::RuntimeError)
+                .attach_printable_lazy(|| format!("empty segment in path {path}"));
+        }
+
+        if !key.is_empty() {
+            segment_v.push(Segment::Key(key));
+        }
+
+        while !rest.is_empty() {
+            let index = rest
+                .strip_prefix('[')
+                .and_then(|rest| rest.find(']').map(|end| &rest[..end]))
+                .and_then(|index| index.parse::<usize>().ok())
+                .ok_or(err::Error::RuntimeError)
+                .attach_printable_lazy(|| format!("invalid index in path {path}"))?;
+
+            segment_v.push(Segment::Index(index));
+            rest = &rest[rest.find(']').unwrap() + 1..];
+        }
+    }
+
+    Ok(segment_v)
+}
+
+/// The value at `path` in `root`, null when something on the way is missing.
+pub fn get<'v>(root: &'v json::JsonValue, path: &str) -> err::Result<&'v json::JsonValue> {
+    Ok(parse_path(path)?
+        .into_iter()
+        .fold(root, |value, segment| match segment {
+            Segment::Key(key) => &value[key],
+            Segment::Index(index) => &value[index],
+        }))
+}
+
+/// Sets the value at `path` in `root`, creating missing objects on the way.
+///
+/// An index may be at most the length of its array, the value is then pushed.
+pub fn set(root: &mut json::JsonValue, path: &str, value: json::JsonValue) -> err::Result<()> {
+    let mut cur = root;
+
+    for segment in parse_path(path)? {
+        cur = match segment {
+            Segment::Key(key) => {
+                if cur.is_null() {
+                    *cur = json::object! {};
+                } else if !cur.is_object() {
+                    return Err(err::Error::RuntimeError)
+                        .attach_printable_lazy(|| format!("{key} of non-object in path {path}"));
+                }
+
+                &mut cur[key]
+            }
+            Segment::Index(index) => {
+                if !cur.is_array() || index > cur.len() {
+                    return Err(err::Error::RuntimeError)
+                        .attach_printable_lazy(|| format!("index {index} out of path {path}"));
+                }
+
+                &mut cur[index]
+            }
+        };
+    }
+
+    *cur = value;
+
+    Ok(())
+}
+
+/// Applies `patch` to `target` as a JSON merge patch, see RFC 7386.
+pub fn merge_patch(target: &mut json::JsonValue, patch: &json::JsonValue) {
+    if !patch.is_object() {
+        *target = patch.clone();
+
+        return;
+    }
+
+    if !target.is_object() {
+        *target = json::object! {};
+    }
+
+    for (key, value) in patch.entries() {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(&mut target[key], value);
+        }
+    }
+}
+
+/// Whether `value` counts as true.
+///
+/// A one-item array, as scripts produce, counts as its item. The strings "", "false" and "0"
+/// are false, and so are null, false, 0 and empty arrays or objects.
+pub fn is_truthy(value: &json::JsonValue) -> bool {
+    if value.is_array() && value.len() == 1 {
+        return is_truthy(&value[0]);
+    }
+
+    match value.as_str() {
+        Some(s) => !s.is_empty() && s != "false" && s != "0",
+        None => !value.is_empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path() {
+        let segment_v = parse_path("a.b[2][0]").unwrap();
+
+        assert!(matches!(
+            segment_v[..],
+            [
+                Segment::Key("a"),
+                Segment::Key("b"),
+                Segment::Index(2),
+                Segment::Index(0)
+            ]
+        ));
+        assert!(parse_path("a..b").is_err());
+        assert!(parse_path("a[x]").is_err());
+        assert!(parse_path("a[1").is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let mut root = json::Null;
+
+        set(&mut root, "a.b", 1.into()).unwrap();
+
+        assert_eq!(root, json::object! { a: { b: 1 } });
+
+        root["a"]["c"] = json::array![1, 2];
+
+        set(&mut root, "a.c[1]", 3.into()).unwrap();
+        set(&mut root, "a.c[2]", 4.into()).unwrap();
+
+        assert_eq!(root["a"]["c"], json::array![1, 3, 4]);
+        assert!(set(&mut root, "a.c[5]", 5.into()).is_err());
+        assert!(set(&mut root, "a.b.d", 5.into()).is_err());
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let mut target = json::object! { a: 1, b: { c: 2, d: 3 } };
+
+        merge_patch(&mut target, &json::object! { a: null, b: { c: 4 }, e: [5] });
+
+        assert_eq!(target, json::object! { b: { c: 4, d: 3 }, e: [5] });
+
+        merge_patch(&mut target, &json::array![1]);
+
+        assert_eq!(target, json::array![1]);
+    }
+}
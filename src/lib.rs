@@ -3,3 +3,4 @@
 pub mod bean;
 pub mod err;
 pub mod def;
+pub mod manager;
@@ -0,0 +1,444 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    pin::Pin,
+};
+
+use error_stack::ResultExt;
+use moon_class::{
+    def::{AsClassManager, Fu},
+    util::rs_2_str,
+};
+
+use crate::{
+    bean::{ElementOp, HostNode, Journal, Mismatch, RenderStat, VNode, VNodeArena, ViewProps},
+    def::{inner, AsElementProvider, AsReconciler, AsViewManager, DEFAULT_MAX_ITERATION},
+    err,
+};
+
+/// Makes a class manager for a layout to run against on its own, see `with_concurrent_layout`.
+type Fork<CM> = fn(&CM) -> Box<dyn AsClassManager>;
+
+/// A complete `AsViewManager` over a class manager `CM` and an element provider `EP`.
+///
+/// Views are read from the `view` class of `CM`; writes made through it lay out again the
+/// vnodes that read what was written.
+pub struct StandardViewManager<CM, EP> {
+    cm: CM,
+    ep: EP,
+    arena: VNodeArena,
+    root_op: Option<u64>,
+    dirty_vnode_v: BTreeMap<u64, Option<ViewProps>>,
+    reconciler_mp: BTreeMap<String, Box<dyn AsReconciler>>,
+    element_op_v: Vec<ElementOp>,
+    journal: Journal,
+    dep_mp: BTreeMap<(String, String), BTreeSet<u64>>,
+    fork_op: Option<Fork<CM>>,
+    max_iteration: usize,
+}
+
+impl<CM, EP> StandardViewManager<CM, EP>
+where
+    CM: AsClassManager,
+    EP: AsElementProvider<H = u64> + Send + Sync,
+{
+    pub fn new(cm: CM, ep: EP) -> Self {
+        Self {
+            cm,
+            ep,
+            arena: VNodeArena::default(),
+            root_op: None,
+            dirty_vnode_v: BTreeMap::new(),
+            reconciler_mp: BTreeMap::new(),
+            element_op_v: vec![],
+            journal: Journal::default(),
+            dep_mp: BTreeMap::new(),
+            fork_op: None,
+            max_iteration: DEFAULT_MAX_ITERATION,
+        }
+    }
+
+    /// Lets sibling layouts run concurrently, each against a clone of `CM`, see
+    /// `AsViewManager::execute_layout_v`.
+    ///
+    /// Clones have to share the classes, like handles to one store, for layouts to read what
+    /// was written through the others.
+    pub fn with_concurrent_layout(mut self) -> Self
+    where
+        CM: Clone + 'static,
+    {
+        self.fork_op = Some(|cm| Box::new(cm.clone()));
+
+        self
+    }
+
+    /// Lets a flush apply one vnode `max_iteration` times before it reports a cycle, see
+    /// `AsViewManager::max_iteration`.
+    pub fn with_max_iteration(mut self, max_iteration: usize) -> Self {
+        self.max_iteration = max_iteration;
+
+        self
+    }
+
+    pub fn cm(&self) -> &CM {
+        &self.cm
+    }
+
+    pub fn ep(&self) -> &EP {
+        &self.ep
+    }
+
+    pub fn ep_mut(&mut self) -> &mut EP {
+        &mut self.ep
+    }
+
+    /// The vnode `entry` was mounted on by `init`.
+    pub fn root_op(&self) -> Option<u64> {
+        self.root_op
+    }
+
+    /// The vnodes matching `selector` in the whole tree, see `AsViewManager::query_all_from`.
+    pub fn query_all(&self, selector: &str) -> err::Result<Vec<u64>> {
+        match self.root_op {
+            Some(root_id) => self.query_all_from(root_id, selector),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// The first vnode matching `selector` in the whole tree.
+    pub fn query(&self, selector: &str) -> err::Result<Option<u64>> {
+        Ok(self.query_all(selector)?.into_iter().next())
+    }
+
+    /// The whole tree as json: the vnodes with their ids, the root and the pending dirty marks.
+    pub fn snapshot(&self) -> json::JsonValue {
+        json::object! {
+            root: self.root_op,
+            arena: self.arena.snapshot(),
+            dirty_vnode_v: self
+                .dirty_vnode_v
+                .iter()
+                .map(|(id, view_props_op)| {
+                    json::object! {
+                        id: *id,
+                        view_props: view_props_op.as_ref().map(ViewProps::to_json),
+                    }
+                })
+                .collect::<Vec<json::JsonValue>>(),
+        }
+    }
+
+    /// Replaces the tree with `snapshot`, creating its elements through the provider without
+    /// laying anything out; the elements of the current tree are deleted.
+    pub fn restore(&mut self, snapshot: &json::JsonValue) -> err::Result<()> {
+        let arena = VNodeArena::restore(&snapshot["arena"])?;
+        let root_op = snapshot["root"].as_u64();
+
+        if let Some(root_id) = root_op {
+            if arena.get(&root_id).is_none() {
+                return Err(err::Error::NotFound)
+                    .attach_printable_lazy(|| format!("root {root_id} not in snapshot"));
+            }
+        }
+
+        let mut dirty_vnode_v = BTreeMap::new();
+
+        for item in snapshot["dirty_vnode_v"].members() {
+            let id = item["id"]
+                .as_u64()
+                .ok_or(err::Error::RuntimeError)
+                .attach_printable_lazy(|| format!("dirty vnode without id: {item}"))?;
+            let view_props_op = if item["view_props"].is_null() {
+                None
+            } else {
+                Some(ViewProps::from_json(&item["view_props"])?)
+            };
+
+            if arena.get(&id).is_none() {
+                return Err(err::Error::NotFound)
+                    .attach_printable_lazy(|| format!("dirty vnode {id} not in snapshot"));
+            }
+
+            dirty_vnode_v.insert(id, view_props_op);
+        }
+
+        self.element_op_v.clear();
+
+        if let Some(root_id) = self.root_op {
+            for id in inner::subtree_id_v(self, root_id) {
+                if inner::has_element(self.get_vnode(&id).unwrap()) {
+                    self.element_op_v.push(ElementOp::Delete { id });
+                }
+            }
+        }
+
+        self.commit();
+
+        self.arena = arena;
+        self.root_op = root_op;
+        self.dirty_vnode_v = dirty_vnode_v;
+        self.journal = Journal::default();
+        self.dep_mp.clear();
+
+        if let Some(root_id) = root_op {
+            for id in inner::subtree_id_v(self, root_id) {
+                let vnode = self.arena.get(&id).unwrap();
+
+                for pair in &vnode.read_set {
+                    self.dep_mp.entry(pair.clone()).or_default().insert(id);
+                }
+
+                // Fragments and vnodes never applied have no element.
+                if !inner::has_element(vnode) {
+                    continue;
+                }
+
+                self.element_op_v.push(ElementOp::Update {
+                    id,
+                    old_class: String::new(),
+                    view_props: vnode.view_props.clone(),
+                });
+            }
+
+            // Created elements are placed by moves, see `AsElementProvider::move_element`.
+            for parent in inner::subtree_id_v(self, root_id) {
+                if !inner::has_element(self.arena.get(&parent).unwrap()) {
+                    continue;
+                }
+
+                for (index, id) in inner::element_child_v(self, parent).into_iter().enumerate() {
+                    self.element_op_v
+                        .push(ElementOp::Move { id, parent, index });
+                }
+            }
+        }
+
+        self.commit();
+
+        Ok(())
+    }
+
+    /// Mounts `entry` as the root, to be laid out by the next flush.
+    fn mount(&mut self, entry: ViewProps) -> err::Result<u64> {
+        if let Some(root_id) = self.root_op {
+            return Err(err::Error::RuntimeError)
+                .attach_printable_lazy(|| format!("already mounted at {root_id}"));
+        }
+
+        let root_id = self.new_vnode(VNode::new(0, None));
+
+        self.get_vnode_mut(&root_id).unwrap().context = root_id;
+        self.root_op = Some(root_id);
+        self.mark_dirty(root_id, Some(entry));
+
+        Ok(root_id)
+    }
+
+    /// Mounts `entry` as the root and renders it, returning the root's id.
+    pub async fn init(&mut self, entry: ViewProps) -> err::Result<u64> {
+        let root_id = self.mount(entry)?;
+
+        self.flush().await?;
+
+        Ok(root_id)
+    }
+
+    /// Like `init`, but adopts the elements the provider already has, e.g. rendered by a server
+    /// with the same views, see `AsViewManager::hydrate_from`.
+    ///
+    /// Returns the root's id and where the elements differ from the vnodes. Vnodes take the
+    /// ids of the elements they adopt, so they match the ones of the rendering manager; the
+    /// root is the first vnode of both.
+    pub async fn hydrate(&mut self, entry: ViewProps) -> err::Result<(u64, Vec<Mismatch>)> {
+        let root_id = self.mount(entry)?;
+
+        let mismatch_v = self.hydrate_from(root_id).await?;
+
+        Ok((root_id, mismatch_v))
+    }
+
+    /// Runs the handler `entry_name` of `vnode_id` with `data`, then renders what it changed.
+    pub async fn dispatch(
+        &mut self,
+        vnode_id: u64,
+        entry_name: &str,
+        data: &json::JsonValue,
+    ) -> err::Result<RenderStat> {
+        self.event_entry(vnode_id, entry_name, data).await?;
+
+        self.flush().await
+    }
+}
+
+impl<CM, EP> AsClassManager for StandardViewManager<CM, EP>
+where
+    CM: AsClassManager,
+    EP: AsElementProvider<H = u64> + Send + Sync,
+{
+    fn remove<'a, 'a1, 'a2, 'f>(
+        &'a mut self,
+        class: &'a1 str,
+        source: &'a2 str,
+        target_v: Vec<String>,
+    ) -> Pin<Box<dyn Fu<Output = moon_class::err::Result<()>> + 'f>>
+    where
+        'a: 'f,
+        'a1: 'f,
+        'a2: 'f,
+    {
+        self.notify_write(class, source);
+
+        self.cm.remove(class, source, target_v)
+    }
+
+    fn get<'a, 'a1, 'a2, 'f>(
+        &'a self,
+        class: &'a1 str,
+        source: &'a2 str,
+    ) -> Pin<Box<dyn Fu<Output = moon_class::err::Result<Vec<String>>> + 'f>>
+    where
+        'a: 'f,
+        'a1: 'f,
+        'a2: 'f,
+    {
+        self.cm.get(class, source)
+    }
+
+    fn append<'a, 'a1, 'a2, 'f>(
+        &'a mut self,
+        class: &'a1 str,
+        source: &'a2 str,
+        item_v: Vec<String>,
+    ) -> Pin<Box<dyn Fu<Output = moon_class::err::Result<()>> + 'f>>
+    where
+        'a: 'f,
+        'a1: 'f,
+        'a2: 'f,
+    {
+        self.notify_write(class, source);
+
+        self.cm.append(class, source, item_v)
+    }
+}
+
+impl<CM, EP> AsElementProvider for StandardViewManager<CM, EP>
+where
+    CM: AsClassManager,
+    EP: AsElementProvider<H = u64> + Send + Sync,
+{
+    type H = u64;
+
+    fn reuse_element(&mut self, id: u64, class: &str, props: &json::JsonValue) -> bool {
+        self.ep.reuse_element(id, class, props)
+    }
+
+    fn delete_element(&mut self, id: u64) {
+        self.ep.delete_element(id)
+    }
+
+    fn create_element(&mut self, vnode_id: u64, class: &str, props: &json::JsonValue) -> u64 {
+        self.ep.create_element(vnode_id, class, props)
+    }
+
+    fn move_element(&mut self, id: u64, parent: u64, index: usize) {
+        self.ep.move_element(id, parent, index)
+    }
+
+    fn attach_portal(&mut self, id: u64, target: &str) {
+        self.ep.attach_portal(id, target)
+    }
+
+    fn host_tree(&self) -> Vec<HostNode> {
+        self.ep.host_tree()
+    }
+
+    fn adopt_element(&mut self, id: u64, class: &str, props: &json::JsonValue) {
+        self.ep.adopt_element(id, class, props)
+    }
+}
+
+impl<CM, EP> AsViewManager for StandardViewManager<CM, EP>
+where
+    CM: AsClassManager,
+    EP: AsElementProvider<H = u64> + Send + Sync,
+{
+    fn dirty_vnode_v_mut(&mut self) -> &mut BTreeMap<u64, Option<ViewProps>> {
+        &mut self.dirty_vnode_v
+    }
+
+    fn dep_mp_mut(&mut self) -> &mut BTreeMap<(String, String), BTreeSet<u64>> {
+        &mut self.dep_mp
+    }
+
+    fn max_iteration(&self) -> usize {
+        self.max_iteration
+    }
+
+    fn fork_class_manager(&self) -> Option<Box<dyn AsClassManager>> {
+        self.fork_op.map(|fork| fork(&self.cm))
+    }
+
+    fn element_op_v_mut(&mut self) -> &mut Vec<ElementOp> {
+        &mut self.element_op_v
+    }
+
+    fn reconciler_mp_mut(&mut self) -> &mut BTreeMap<String, Box<dyn AsReconciler>> {
+        &mut self.reconciler_mp
+    }
+
+    fn get_class_view<'a, 'a1, 'f>(
+        &'a self,
+        class: &'a1 str,
+    ) -> Pin<Box<dyn Fu<Output = Option<String>> + 'f>>
+    where
+        'a: 'f,
+        'a1: 'f,
+    {
+        Box::pin(async move {
+            match self.cm.get("view", class).await {
+                Ok(rs) if !rs.is_empty() => Some(rs_2_str(&rs)),
+                Ok(_) => None,
+                Err(e) => {
+                    log::warn!("failed to get view of {class}: {e:?}");
+
+                    None
+                }
+            }
+        })
+    }
+
+    fn journal_mut(&mut self) -> &mut Journal {
+        &mut self.journal
+    }
+
+    fn get_vnode(&self, id: &u64) -> Option<&VNode> {
+        self.arena.get(id)
+    }
+
+    fn get_vnode_mut(&mut self, id: &u64) -> Option<&mut VNode> {
+        self.arena.get_mut(id)
+    }
+
+    fn new_vnode(&mut self, vnode: VNode) -> u64 {
+        self.arena.insert(vnode)
+    }
+
+    fn rm_vnode(&mut self, id: u64) -> Option<VNode> {
+        self.arena.remove(id)
+    }
+
+    fn insert_vnode(&mut self, id: u64, vnode: VNode) -> err::Result<()> {
+        self.arena.insert_at(id, vnode)
+    }
+
+    fn reserve_vnode_id_v(&mut self, id_v: &[u64]) {
+        self.arena.reserve(id_v)
+    }
+
+    fn release_vnode_id_v(&mut self, id_v: &[u64]) {
+        self.arena.release(id_v)
+    }
+
+    fn new_vnode_at(&mut self, id: u64, vnode: VNode) -> err::Result<()> {
+        self.arena.insert_reserved(id, vnode)
+    }
+}